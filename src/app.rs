@@ -1,20 +1,30 @@
 use std::{fmt::Debug, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Error, Result};
 use tokio::sync::{mpsc::Sender, oneshot};
 use web_rwkv::{context::Context, tokenizer::Tokenizer};
 
 use crate::{
     components::{
-        model::AxumModel, pipeline::Pipelines, softmax::Softmax, state::InferStates, Registry,
+        infer::{
+            embedding::{l2_normalize, Pooling},
+            logprobs::logprob,
+        },
+        model::AxumModel,
+        pipeline::Pipelines,
+        softmax::Softmax,
+        state::InferStates,
+        transformer::grammar_cache::GrammarCache,
+        Registry,
     },
-    config::ModelConfig,
+    config::{ModelConfig, PreloadSpec, PreloadState},
 };
 
 pub struct InnerState {
     pub config: ModelConfig,
     pub pipelines: Arc<Pipelines>,
     pub registry: Arc<Registry>,
+    pub grammars: GrammarCache,
     pub states: InferStates,
     softmax_queue: Sender<Vec<(Vec<f32>, oneshot::Sender<Vec<f32>>)>>,
     pub tokenizer: Arc<Tokenizer>,
@@ -34,16 +44,87 @@ impl AppState {
         let softmax = Softmax::new(model.clone(), config.model.get_max_concurrency()).await;
         let (softmax_sender, _) = softmax.run().await;
 
-        Ok(AppState(Arc::new(InnerState {
+        let tokenizer = config.tokenizer.load_tokenizer().await?;
+        let state = AppState(Arc::new(InnerState {
             config: config.clone(),
             pipelines: Arc::new(Pipelines::new()),
             registry: Arc::new(Registry::new()),
             softmax_queue: softmax_sender,
-            tokenizer: Arc::new(config.tokenizer.load_tokenizer().await?),
+            grammars: GrammarCache::new(
+                &tokenizer,
+                config.axum.get_grammar_cache_size(),
+                config.axum.get_grammar_cache_max_bytes(),
+            ),
+            tokenizer: Arc::new(tokenizer),
             context: context.clone(),
             model: model.clone(),
             states: InferStates::new(config, context.clone(), model.clone())?,
-        })))
+        }));
+        state.preload().await?;
+        Ok(state)
+    }
+
+    /// Creates the preloaded pipelines and states in the config, and marks them
+    /// as read-only.
+    async fn preload(&self) -> Result<()> {
+        let PreloadSpec { pipelines, states } = &self.0.config.preload;
+
+        for (index, payload) in pipelines.iter().enumerate() {
+            let id = self
+                .0
+                .pipelines
+                .create_pipeline(self, payload.clone())
+                .await
+                .map_err(|e| {
+                    Error::msg(format!("Failed to preload pipeline at index {index}: {e}"))
+                })?;
+            self.0.pipelines.mark_readonly(&id).await;
+        }
+
+        for PreloadState {
+            id,
+            dump_id,
+            prompt,
+        } in states
+        {
+            self.preload_state(id, dump_id.clone(), prompt.as_deref())
+                .await
+                .map_err(|e| Error::msg(format!("Failed to preload state {id}: {e}")))?;
+            self.0.states.mark_readonly(id).await;
+        }
+
+        if !pipelines.is_empty() || !states.is_empty() {
+            println!(
+                "Preloaded {} pipelines and {} states.",
+                pipelines.len(),
+                states.len()
+            );
+        }
+        Ok(())
+    }
+
+    async fn preload_state(
+        &self,
+        id: &str,
+        dump_id: Option<String>,
+        prompt: Option<&str>,
+    ) -> Result<()> {
+        match dump_id {
+            Some(dump_id) => self.load_state(id.to_string(), dump_id).await?,
+            None => self.0.states.create_state(id).await?,
+        }
+        if let Some(prompt) = prompt {
+            let tokens = self.tokenize(&prompt.as_bytes().to_vec())?;
+            if !tokens.is_empty() {
+                self.0
+                    .states
+                    .create_ticket(vec![id.to_string()])
+                    .await?
+                    .infer(vec![tokens])
+                    .await;
+            }
+        }
+        Ok(())
     }
 
     pub async fn update_state(
@@ -65,6 +146,135 @@ impl AppState {
         }
     }
 
+    /// Scores candidate token sequences conditioned on a state, returning the
+    /// log-probability of each candidate token. The state is forked for each
+    /// candidate, so the original state is never mutated.
+    ///
+    /// As a state doesn't keep the logits of its last position, `context` is
+    /// fed before each candidate to obtain the distribution of the first token.
+    /// Without `context`, the first token of each candidate is only used as
+    /// condition and is not scored, so every candidate needs at least 2 tokens.
+    pub async fn score(
+        &self,
+        id: &str,
+        context: Option<Vec<u16>>,
+        candidates: Vec<Vec<u16>>,
+    ) -> Result<Vec<Vec<f32>>> {
+        if candidates.iter().any(|x| x.is_empty()) {
+            return Err(Error::msg("Candidate must not be empty!"));
+        }
+        if context.as_ref().is_some_and(|x| x.is_empty()) {
+            return Err(Error::msg("Context must not be empty!"));
+        }
+        if context.is_none() && candidates.iter().any(|x| x.len() < 2) {
+            return Err(Error::msg(
+                "Candidate must have at least 2 tokens without context!",
+            ));
+        }
+        let offset = if context.is_some() { 0 } else { 1 };
+
+        let mut scores = Vec::with_capacity(candidates.len());
+        for chunk in candidates.chunks(self.0.config.model.get_max_concurrency()) {
+            let forks = self.0.states.fork_state(id, chunk.len()).await?;
+            let mut ticket = self.0.states.create_ticket_from(forks).await;
+            let max_len = chunk.iter().map(|x| x.len()).max().unwrap_or_default();
+
+            let mut logits = ticket
+                .infer(
+                    chunk
+                        .iter()
+                        .map(|x| context.clone().unwrap_or_else(|| x[..1].to_vec()))
+                        .collect(),
+                )
+                .await;
+            let mut chunk_scores = vec![Vec::with_capacity(max_len); chunk.len()];
+            for step in offset..max_len {
+                let probs = self.softmax(logits).await;
+                for ((candidate, probs), scores) in
+                    chunk.iter().zip(probs).zip(chunk_scores.iter_mut())
+                {
+                    if let Some(&token) = candidate.get(step) {
+                        scores.push(logprob(probs[token as usize]));
+                    }
+                }
+                if step + 1 == max_len {
+                    break;
+                }
+                // Finished candidates are padded, as every state in a ticket
+                // must be fed. Forks are discarded so this is harmless.
+                logits = ticket
+                    .infer(
+                        chunk
+                            .iter()
+                            .map(|x| vec![x.get(step).copied().unwrap_or_default()])
+                            .collect(),
+                    )
+                    .await;
+            }
+            scores.extend(chunk_scores);
+        }
+        Ok(scores)
+    }
+
+    /// Feeds each text into a scratch state and pools an embedding from it.
+    pub async fn embed(
+        &self,
+        texts: Vec<Vec<u16>>,
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<Vec<f32>>> {
+        if texts.iter().any(|x| x.is_empty()) {
+            return Err(Error::msg("Text must not be empty!"));
+        }
+        let num_layer = self.0.model.info().num_layer;
+        if let Pooling::Layer(layer) = pooling {
+            if layer >= num_layer {
+                return Err(Error::msg(format!(
+                    "Layer {layer} is out of range, the model has {num_layer} layers!"
+                )));
+            }
+        }
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(self.0.config.model.get_max_concurrency()) {
+            let scratches = self.0.states.scratch_states(chunk.len());
+            let logits = self
+                .0
+                .states
+                .create_ticket_from(scratches.clone())
+                .await
+                .infer(chunk.to_vec())
+                .await;
+            if let Pooling::Logits = pooling {
+                embeddings.extend(logits);
+                continue;
+            }
+            for scratch in scratches {
+                self.0.states.sync_fork(&scratch).await;
+                embeddings.push(match pooling {
+                    Pooling::LastLayer => scratch.embed(num_layer - 1).await,
+                    Pooling::Layer(layer) => scratch.embed(layer).await,
+                    Pooling::MeanLayers => {
+                        let mut sum = scratch.embed(0).await;
+                        for layer in 1..num_layer {
+                            for (x, y) in sum.iter_mut().zip(scratch.embed(layer).await) {
+                                *x += y;
+                            }
+                        }
+                        sum.iter_mut().for_each(|x| *x /= num_layer as f32);
+                        sum
+                    }
+                    Pooling::Logits => unreachable!(),
+                });
+            }
+        }
+        Ok(if normalize {
+            embeddings.into_iter().map(l2_normalize).collect()
+        } else {
+            embeddings
+        })
+    }
+
     pub async fn dump_state(&self, id: String, dump_id: String) -> Result<()> {
         self.0
             .states
@@ -86,6 +296,24 @@ impl AppState {
             .await
     }
 
+    pub async fn dump_pipeline(&self, id: String, dump_id: String) -> Result<()> {
+        self.0
+            .pipelines
+            .dump_pipeline(&id, self.0.config.axum.get_pipeline_dump().join(dump_id))
+            .await
+    }
+
+    pub async fn load_pipeline(&self, id: String, dump_id: String) -> Result<()> {
+        self.0
+            .pipelines
+            .load_pipeline(
+                self,
+                &id,
+                self.0.config.axum.get_pipeline_dump().join(dump_id),
+            )
+            .await
+    }
+
     pub fn tokenize(&self, input: &Vec<u8>) -> Result<Vec<u16>> {
         Ok(self.0.tokenizer.encode(&input)?)
     }
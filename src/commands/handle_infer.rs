@@ -1,36 +1,112 @@
 use std::time::Duration;
 
 use anyhow::{Error, Result};
+use futures_util::future::join_all;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::time::timeout;
 
 use crate::{
     app::AppState,
-    components::infer::{
-        tokens::to_tokens,
-        updates::{ResetSetting, UpdateSetting},
+    components::{
+        infer::{
+            beam::{beam_search as run_beam_search, BeamSetting, Hypothesis},
+            healing::TokenHealing,
+            logprobs::TokenLogprobs,
+            tokens::to_tokens,
+            trace::StepTrace,
+            updates::{ResetSetting, UpdateSetting},
+        },
+        pipeline::{
+            pipeline::{InferOptions, InferResult},
+            ForkPolicy,
+        },
     },
 };
 
+#[derive(Debug, Serialize)]
+struct InferResponse {
+    prompt_tokens: usize,
+    inferred_tokens: usize,
+    result: String,
+    last_token: u16,
+    end_reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cumulative_logprob: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprobs>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<Vec<StepTrace>>,
+}
+
+impl InferResponse {
+    fn new(
+        state: &AppState,
+        prompt_tokens: usize,
+        result: InferResult,
+        healing: Option<&TokenHealing>,
+    ) -> Result<Self> {
+        let InferResult {
+            last_token,
+            inferred_tokens,
+            end_reason,
+            logprobs,
+            trace,
+            seed,
+        } = result;
+        let mut decoded = state.0.tokenizer.decode(&inferred_tokens)?;
+        if let Some(healing) = healing {
+            decoded = healing.reconcile(&decoded);
+        }
+        Ok(Self {
+            prompt_tokens,
+            inferred_tokens: inferred_tokens.len(),
+            result: String::from_utf8_lossy(&decoded).to_string(),
+            last_token,
+            end_reason,
+            cumulative_logprob: None,
+            logprobs,
+            seed,
+            trace,
+        })
+    }
+}
+
+/// Which branch should be kept when multiple samples are inferred.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeepBranch {
+    Index(usize),
+    Criterion(KeepCriterion),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeepCriterion {
+    /// The branch with the highest cumulative logprob.
+    Best,
+}
+
 pub async fn infer(data: Option<Value>, state: AppState) -> Result<Value> {
     #[derive(Debug, Deserialize)]
     struct InferPayload {
         tokens: Vec<Value>,
         states: Vec<String>,
-        pipeline: String,
+        pipeline: Value,
         update_prompt: Option<Value>,
         reset_on_exhaustion: Option<Value>,
         timeout: Option<usize>,
-    }
-
-    #[derive(Debug, Serialize)]
-    struct InferResponse {
-        prompt_tokens: usize,
-        inferred_tokens: usize,
-        result: String,
-        last_token: u16,
-        end_reason: &'static str,
+        logprobs: Option<usize>,
+        n: Option<usize>,
+        keep: Option<KeepBranch>,
+        fork: Option<ForkPolicy>,
+        #[serde(default)]
+        trace: bool,
+        seed: Option<u64>,
+        #[serde(default)]
+        token_healing: bool,
     }
 
     let InferPayload {
@@ -40,47 +116,340 @@ pub async fn infer(data: Option<Value>, state: AppState) -> Result<Value> {
         update_prompt,
         reset_on_exhaustion,
         timeout: timeout_millis,
+        logprobs,
+        n,
+        keep,
+        fork,
+        trace,
+        seed,
+        token_healing,
     } = serde_json::from_value(data.ok_or(Error::msg("Payload required!"))?)?;
 
-    let tokens = tokens
+    let mut tokens = tokens
         .into_iter()
         .map(|x| to_tokens(&state, x))
         .collect::<Result<Vec<_>>>()?;
 
     let prompt_tokens = tokens.iter().fold(0, |x, y| x + y.len());
+    // The prompts are healed before anything is fed, including the kept branch.
+    let healing = if token_healing {
+        TokenHealing::new(&state, &mut tokens)?
+    } else {
+        None
+    };
+    let timeout_duration = Duration::from_millis(timeout_millis.unwrap_or(20 * 1000) as u64);
 
-    let ticket = timeout(
-        Duration::from_millis(timeout_millis.unwrap_or(20 * 1000) as u64),
-        state.0.states.create_ticket(states),
-    )
-    .await??;
-
-    let pipeline = state.0.pipelines.get_pipeline(&pipeline).await?;
-
-    let mut lock = pipeline.lock().await;
-    let update_setting = UpdateSetting::from_value(&lock.get_transformer_shape(), update_prompt)?;
-    let reset_setting =
-        ResetSetting::from_value(&lock.get_transformer_shape(), reset_on_exhaustion)?;
-
-    let (last_token, inferred_tokens, end_reason) = {
-        lock.infer(
-            ticket,
-            reset_setting,
-            update_setting,
-            tokens,
-            state.0.config.model.get_max_infer_tokens(),
+    let Some(n) = n else {
+        let ticket = timeout(timeout_duration, state.0.states.create_ticket(states)).await??;
+
+        let pipeline = match fork {
+            Some(ForkPolicy::Discard) => {
+                state.0.pipelines.resolve_pipeline(&state, pipeline).await?
+            }
+            _ => {
+                state
+                    .0
+                    .pipelines
+                    .resolve_pipeline_mut(&state, pipeline)
+                    .await?
+            }
+        };
+
+        let options = InferOptions {
+            max_tokens: state.0.config.model.get_max_infer_tokens(),
+            logprobs,
+            trace,
+            seed,
+            healing: healing.clone(),
+        };
+
+        let result = if let Some(policy) = fork {
+            // Generation runs on a copy of the pipeline, so the lock is only held
+            // when copying and writing back.
+            let (mut forked, update_setting, reset_setting) = {
+                let lock = pipeline.lock().await;
+                (
+                    lock.clone(),
+                    UpdateSetting::from_value(&lock.get_transformer_shape(), update_prompt)?,
+                    ResetSetting::from_value(&lock.get_transformer_shape(), reset_on_exhaustion)?,
+                )
+            };
+            let result = forked
+                .infer(
+                    ticket,
+                    reset_setting,
+                    update_setting,
+                    tokens,
+                    options,
+                    &state,
+                )
+                .await?;
+            if let ForkPolicy::Overwrite = policy {
+                *pipeline.lock().await = forked;
+            }
+            result
+        } else {
+            let mut lock = pipeline.lock().await;
+            let update_setting =
+                UpdateSetting::from_value(&lock.get_transformer_shape(), update_prompt)?;
+            let reset_setting =
+                ResetSetting::from_value(&lock.get_transformer_shape(), reset_on_exhaustion)?;
+
+            lock.infer(
+                ticket,
+                reset_setting,
+                update_setting,
+                tokens,
+                options,
+                &state,
+            )
+            .await?
+        };
+
+        return Ok(serde_json::to_value(InferResponse::new(
             &state,
+            prompt_tokens,
+            result,
+            healing.as_ref(),
+        )?)?);
+    };
+
+    let state_count = states.len();
+    let max_concurrency = state.0.config.model.get_max_concurrency();
+    if n == 0 || n * state_count > max_concurrency {
+        return Err(Error::msg(format!(
+            "n must be between 1 and {} for {state_count} states!",
+            max_concurrency / state_count.max(1)
+        )));
+    }
+    if fork.is_some() {
+        return Err(Error::msg(
+            "fork can't be used with n, use keep to write back a branch instead!",
+        ));
+    }
+    // Each branch starts from the last token of the prompts.
+    if tokens.iter().any(|x| x.is_empty()) {
+        return Err(Error::msg("Prompt must not be empty!"));
+    }
+    if let Some(KeepBranch::Index(index)) = keep {
+        if index >= n {
+            return Err(Error::msg("Kept branch index is out of range!"));
+        }
+    }
+    if keep.is_some() {
+        state.0.states.ensure_writable(&states).await?;
+    }
+
+    // The prompts except their last tokens are inferred once into a fork of
+    // each state, and the branches are forked from there.
+    let (heads, tails): (Vec<_>, Vec<_>) = tokens
+        .iter()
+        .map(|x| x.split_at(x.len() - 1))
+        .map(|(head, tail)| (head.to_vec(), tail.to_vec()))
+        .unzip();
+    let mut roots = Vec::with_capacity(state_count);
+    for id in states.iter() {
+        roots.push(state.0.states.fork_state(id, 1).await?.remove(0));
+    }
+    let (fed, heads): (Vec<_>, Vec<_>) = roots
+        .iter()
+        .cloned()
+        .zip(heads)
+        .filter(|(_, head)| !head.is_empty())
+        .unzip();
+    if !fed.is_empty() {
+        timeout(timeout_duration, state.0.states.create_ticket_from(fed))
+            .await?
+            .infer(heads)
+            .await;
+    }
+
+    // Forks are laid out branch by branch, so each branch gets a sub-ticket
+    // while all of them are inferred as one batch.
+    let mut forks = Vec::with_capacity(n * state_count);
+    for _ in 0..n {
+        for root in roots.iter() {
+            forks.push(state.0.states.fork_named(root).await?);
+        }
+    }
+    let tickets = timeout(
+        timeout_duration,
+        state.0.states.create_ticket_from(forks.clone()),
+    )
+    .await?
+    .split(state_count);
+
+    let pipeline = if keep.is_some() {
+        state
+            .0
+            .pipelines
+            .resolve_pipeline_mut(&state, pipeline)
+            .await?
+    } else {
+        state.0.pipelines.resolve_pipeline(&state, pipeline).await?
+    };
+
+    // Branches run on copies of the pipeline, so the lock is only held when
+    // copying and writing back the kept branch. The prompts are updated once
+    // before copying.
+    let (mut base, update_setting, reset_setting) = {
+        let lock = pipeline.lock().await;
+        (
+            lock.clone(),
+            UpdateSetting::from_value(&lock.get_transformer_shape(), update_prompt)?,
+            ResetSetting::from_value(&lock.get_transformer_shape(), reset_on_exhaustion)?,
         )
+    };
+    base.update_prompt(&tokens, update_setting)?;
+    let update_setting =
+        UpdateSetting::from_value(&base.get_transformer_shape(), Some(Value::Bool(false)))?;
+    // Logprobs are always recorded to get the cumulative logprob
+    let options = InferOptions {
+        max_tokens: state.0.config.model.get_max_infer_tokens(),
+        logprobs: Some(logprobs.unwrap_or(0)),
+        trace,
+        seed: None,
+        healing: healing.clone(),
+    };
+    // Branches are copies of a same sampler, so they're seeded apart.
+    let base_seed = seed.unwrap_or_else(rand::random);
+
+    let app_state = &state;
+    let branches = join_all(tickets.into_iter().enumerate().map(|(index, ticket)| {
+        let mut pipeline = base.clone();
+        let options = InferOptions {
+            seed: Some(base_seed.wrapping_add(index as u64)),
+            ..options.clone()
+        };
+        let reset_setting = reset_setting.clone();
+        let update_setting = update_setting.clone();
+        let tails = tails.clone();
+        async move {
+            let result = pipeline
+                .infer(
+                    ticket,
+                    reset_setting,
+                    update_setting,
+                    tails,
+                    options,
+                    app_state,
+                )
+                .await?;
+            Ok((pipeline, result))
+        }
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?;
+
+    let cumulative_logprobs = branches
+        .iter()
+        .map(|(_, result)| {
+            result
+                .logprobs
+                .iter()
+                .flatten()
+                .map(|x| x.sampled.logprob)
+                .sum::<f32>()
+        })
+        .collect::<Vec<_>>();
+
+    let kept = keep.map(|keep| match keep {
+        KeepBranch::Index(index) => index,
+        KeepBranch::Criterion(KeepCriterion::Best) => cumulative_logprobs
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(index, _)| index)
+            .unwrap(),
+    });
+
+    let mut responses = Vec::with_capacity(n);
+    for (index, ((branch_pipeline, result), cumulative_logprob)) in
+        branches.into_iter().zip(cumulative_logprobs).enumerate()
+    {
+        if Some(index) == kept {
+            // The forks of the kept branch hold the prompts and the generation
+            // except the last token, which is returned to continue the
+            // generation, so they're written back into the source states.
+            let kept_forks = &forks[index * state_count..(index + 1) * state_count];
+            for (id, fork) in states.iter().zip(kept_forks) {
+                state.0.states.overwrite_state(id, fork).await?;
+            }
+            *pipeline.lock().await = branch_pipeline;
+        }
+        let mut response = InferResponse::new(&state, prompt_tokens, result, healing.as_ref())?;
+        response.cumulative_logprob = Some(cumulative_logprob);
+        if logprobs.is_none() {
+            response.logprobs = None;
+        }
+        responses.push(response);
     }
-    .await?;
 
-    drop(lock);
+    Ok(serde_json::to_value(responses)?)
+}
+
+pub async fn beam_search(data: Option<Value>, state: AppState) -> Result<Value> {
+    #[derive(Debug, Deserialize)]
+    struct BeamSearchPayload {
+        tokens: Value,
+        state: String,
+        pipeline: Option<Value>,
+        #[serde(flatten)]
+        setting: BeamSetting,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct BeamSearchResponse {
+        result: String,
+        tokens: Vec<u16>,
+        last_token: u16,
+        logprob: f32,
+        score: f32,
+    }
+
+    let BeamSearchPayload {
+        tokens,
+        state: state_id,
+        pipeline,
+        setting,
+    } = serde_json::from_value(data.ok_or(Error::msg("Payload required!"))?)?;
+
+    let tokens = to_tokens(&state, tokens)?;
+    let pipeline = match pipeline {
+        Some(pipeline) => Some(
+            state
+                .0
+                .pipelines
+                .resolve_pipeline(&state, pipeline)
+                .await?
+                .lock()
+                .await
+                .clone(),
+        ),
+        None => None,
+    };
 
-    Ok(serde_json::to_value(InferResponse {
-        prompt_tokens,
-        inferred_tokens: inferred_tokens.len(),
-        result: String::from_utf8_lossy(&state.0.tokenizer.decode(&inferred_tokens)?).to_string(),
-        last_token,
-        end_reason,
-    })?)
+    let hypotheses = run_beam_search(&state, state_id, tokens, pipeline, setting).await?;
+    Ok(serde_json::to_value(
+        hypotheses
+            .into_iter()
+            .map(
+                |Hypothesis {
+                     tokens,
+                     logprob,
+                     score,
+                 }| {
+                    Ok(BeamSearchResponse {
+                        result: String::from_utf8_lossy(&state.0.tokenizer.decode(&tokens)?)
+                            .to_string(),
+                        last_token: *tokens.last().unwrap(),
+                        tokens,
+                        logprob,
+                        score,
+                    })
+                },
+            )
+            .collect::<Result<Vec<_>>>()?,
+    )?)
 }
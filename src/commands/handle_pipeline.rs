@@ -1,6 +1,11 @@
-use crate::{app::AppState, components::pipeline::mutate::Modification};
+use crate::{
+    app::AppState,
+    components::{
+        infer::updates::ResetSetting, pipeline::mutate::Modification, InferenceInterruption,
+    },
+};
 use anyhow::{Error, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 pub async fn create_pipeline(data: Option<Value>, state: AppState) -> Result<Value> {
@@ -47,7 +52,7 @@ pub async fn reset_pipeline(data: Option<Value>, state: AppState) -> Result<Valu
     state
         .0
         .pipelines
-        .get_pipeline(&serde_json::from_value::<String>(
+        .get_pipeline_mut(&serde_json::from_value::<String>(
             data.ok_or(Error::msg("Payload required"))?,
         )?)
         .await?
@@ -66,12 +71,117 @@ pub async fn modify_pipeline(data: Option<Value>, state: AppState) -> Result<Val
     let Modify { id, modifications } =
         serde_json::from_value(data.ok_or(Error::msg("Payload required"))?)?;
 
-    let pipeline = state.0.pipelines.get_pipeline(&id).await?;
-    {
-        let mut lock = pipeline.lock().await;
-        for modification in modifications {
-            modification.modify(&mut lock, &state)?;
-        }
+    let pipeline = state.0.pipelines.get_pipeline_mut(&id).await?;
+    Modification::modify_all(modifications, &mut *pipeline.lock().await, &state)?;
+    Ok(Value::Null)
+}
+
+pub async fn dump_pipeline(data: Option<Value>, state: AppState) -> Result<Value> {
+    #[derive(Deserialize)]
+    struct Dump {
+        id: String,
+        dump_id: String,
     }
+    let Dump { id, dump_id } = serde_json::from_value(data.ok_or(Error::msg("Payload required"))?)?;
+
+    state.dump_pipeline(id, dump_id).await?;
     Ok(Value::Null)
 }
+
+pub async fn load_pipeline(data: Option<Value>, state: AppState) -> Result<Value> {
+    #[derive(Deserialize)]
+    struct Load {
+        id: String,
+        dump_id: String,
+    }
+    let Load { id, dump_id } = serde_json::from_value(data.ok_or(Error::msg("Payload required"))?)?;
+
+    state.load_pipeline(id, dump_id).await?;
+    Ok(Value::Null)
+}
+
+pub async fn pipeline_step(data: Option<Value>, state: AppState) -> Result<Value> {
+    #[derive(Deserialize)]
+    struct Step {
+        pipeline: Value,
+        logits: Vec<Vec<f32>>,
+        force_token: Option<u16>,
+        reset_on_exhaustion: Option<Value>,
+    }
+    #[derive(Serialize)]
+    struct StepResponse {
+        probs: Vec<Vec<f32>>,
+        token: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exhausted: Option<bool>,
+    }
+    let Step {
+        pipeline,
+        logits,
+        force_token,
+        reset_on_exhaustion,
+    } = serde_json::from_value(data.ok_or(Error::msg("Payload required"))?)?;
+
+    let pipeline = if force_token.is_some() {
+        state
+            .0
+            .pipelines
+            .resolve_pipeline_mut(&state, pipeline)
+            .await?
+    } else {
+        state.0.pipelines.resolve_pipeline(&state, pipeline).await?
+    };
+    let mut pipeline = pipeline.lock().await;
+
+    let slot_count = pipeline.get_transformer_shape().len();
+    if logits.len() != slot_count {
+        return Err(Error::msg(format!(
+            "Pipeline has {slot_count} state slots, but {} logits are given!",
+            logits.len()
+        )));
+    }
+    let num_vocab = state.0.model.info().num_vocab;
+    if logits.iter().any(|x| x.len() != num_vocab) {
+        return Err(Error::msg(format!(
+            "Each logits must have exactly {num_vocab} values!"
+        )));
+    }
+    // Non-finite logits give NaN probs, which samplers can't sort.
+    if logits.iter().flatten().any(|x| !x.is_finite()) {
+        return Err(Error::msg("Logits must be finite!"));
+    }
+    let reset_setting =
+        ResetSetting::from_value(&pipeline.get_transformer_shape(), reset_on_exhaustion)?;
+
+    let (probs, token) = match force_token {
+        Some(_) => pipeline.step(logits, &state).await,
+        // Without a forced token, the step runs on a copy so the stored
+        // pipeline, including the RNG of its sampler, is left untouched.
+        None => pipeline.clone().step(logits, &state).await,
+    };
+    let exhausted = match force_token {
+        Some(forced) => match pipeline.update_auto(&vec![vec![forced]; slot_count]) {
+            Ok(_) => Some(false),
+            Err(InferenceInterruption::Exhaustion) => {
+                pipeline.reset(reset_setting);
+                Some(true)
+            }
+            Err(InferenceInterruption::Error(e)) => Err(e)?,
+        },
+        None => None,
+    };
+
+    Ok(serde_json::to_value(StepResponse {
+        probs,
+        token,
+        exhausted,
+    })?)
+}
+
+pub async fn grammar_cache_stats(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(serde_json::to_value(state.0.grammars.stats())?)
+}
+
+pub async fn clear_grammar_cache(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(serde_json::to_value(state.0.grammars.clear())?)
+}
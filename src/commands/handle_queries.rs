@@ -0,0 +1,97 @@
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{
+        infer::{embedding::Pooling, tokens::to_tokens},
+        transformer::watermark::WatermarkScheme,
+    },
+};
+
+#[derive(Debug, Deserialize)]
+struct ScorePayload {
+    state: String,
+    context: Option<Value>,
+    candidates: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct CandidateScore {
+    logprobs: Vec<f32>,
+    total: f32,
+    perplexity: f32,
+}
+
+pub async fn score(data: Option<Value>, state: AppState) -> Result<Value> {
+    let ScorePayload {
+        state: state_id,
+        context,
+        candidates,
+    } = serde_json::from_value(data.ok_or(Error::msg("Payload required!"))?)?;
+
+    let context = context.map(|x| to_tokens(&state, x)).transpose()?;
+    let candidates = candidates
+        .into_iter()
+        .map(|x| to_tokens(&state, x))
+        .collect::<Result<Vec<_>>>()?;
+
+    let scores = state
+        .score(&state_id, context, candidates)
+        .await?
+        .into_iter()
+        .map(|logprobs| {
+            let total = logprobs.iter().sum::<f32>();
+            CandidateScore {
+                perplexity: (-total / logprobs.len() as f32).exp(),
+                logprobs,
+                total,
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_value(scores)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbedPayload {
+    texts: Vec<Value>,
+    #[serde(default)]
+    pooling: Pooling,
+    #[serde(default)]
+    normalize: bool,
+}
+
+pub async fn embed(data: Option<Value>, state: AppState) -> Result<Value> {
+    let EmbedPayload {
+        texts,
+        pooling,
+        normalize,
+    } = serde_json::from_value(data.ok_or(Error::msg("Payload required!"))?)?;
+
+    let texts = texts
+        .into_iter()
+        .map(|x| to_tokens(&state, x))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(serde_json::to_value(
+        state.embed(texts, pooling, normalize).await?,
+    )?)
+}
+
+#[derive(Debug, Deserialize)]
+struct DetectWatermarkPayload {
+    text: Value,
+    #[serde(flatten)]
+    scheme: WatermarkScheme,
+}
+
+pub async fn detect_watermark(data: Option<Value>, state: AppState) -> Result<Value> {
+    let DetectWatermarkPayload { text, scheme } =
+        serde_json::from_value(data.ok_or(Error::msg("Payload required!"))?)?;
+    scheme.validate()?;
+
+    // Only the tokenizer is needed, so the model is not touched.
+    let tokens = to_tokens(&state, text)?;
+    Ok(serde_json::to_value(scheme.detect(&tokens)?)?)
+}
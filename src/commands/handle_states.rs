@@ -8,16 +8,35 @@ use crate::{app::AppState, components::infer::tokens::to_token_vec};
 struct StateCreate {
     id: String,
     dump_id: Option<String>,
+    pipeline: Option<String>,
 }
 
 #[inline]
 pub async fn create_state(data: Option<Value>, state: AppState) -> Result<Value> {
-    let StateCreate { id, dump_id } = serde_json::from_value(
+    let StateCreate {
+        id,
+        dump_id,
+        pipeline,
+    } = serde_json::from_value(
         data.ok_or(Error::msg("Field data is needed to specify state id!"))?,
     )?;
-    match dump_id {
-        Some(dump_id) => state.load_state(id, dump_id).await?,
-        None => state.0.states.create_state(id.as_str()).await?,
+    match (dump_id, pipeline) {
+        (Some(dump_id), Some(pipeline)) => {
+            state
+                .load_pipeline(pipeline.clone(), dump_id.clone())
+                .await?;
+            // The pipeline is removed again if the state fails to load, so a
+            // failed call leaves nothing behind.
+            if let Err(err) = state.load_state(id, dump_id).await {
+                let _ = state.0.pipelines.remove_pipeline(&pipeline).await;
+                return Err(err);
+            }
+        }
+        (Some(dump_id), None) => state.load_state(id, dump_id).await?,
+        (None, Some(_)) => {
+            return Err(Error::msg("A dump id is needed to load the pipeline from!"))
+        }
+        (None, None) => state.0.states.create_state(id.as_str()).await?,
     };
     Ok(Value::Null)
 }
@@ -98,13 +117,20 @@ pub async fn update_state(data: Option<Value>, state: AppState) -> Result<Value>
 struct StateDump {
     state_id: String,
     dump_id: String,
+    pipeline: Option<String>,
 }
 
 #[inline]
 pub async fn dump_state(data: Option<Value>, state: AppState) -> Result<Value> {
-    let StateDump { state_id, dump_id } =
-        serde_json::from_value(data.ok_or(Error::msg("Field empty!"))?)?;
+    let StateDump {
+        state_id,
+        dump_id,
+        pipeline,
+    } = serde_json::from_value(data.ok_or(Error::msg("Field empty!"))?)?;
 
+    if let Some(pipeline) = pipeline {
+        state.dump_pipeline(pipeline, dump_id.clone()).await?;
+    }
     state.dump_state(state_id, dump_id).await?;
     Ok(Value::Null)
 }
@@ -6,6 +6,7 @@ use crate::{app::AppState, register_handlers};
 
 mod handle_infer;
 mod handle_pipeline;
+mod handle_queries;
 mod handle_states;
 
 pub mod types;
@@ -32,12 +33,22 @@ impl TextCommand {
                 handle_states::delete_dump,
                 //Infer
                 handle_infer::infer,
+                handle_infer::beam_search,
+                //Queries
+                handle_queries::score,
+                handle_queries::embed,
+                handle_queries::detect_watermark,
                 //Pipeline
                 handle_pipeline::create_pipeline,
                 handle_pipeline::copy_pipeline,
                 handle_pipeline::delete_pipeline,
                 handle_pipeline::reset_pipeline,
                 handle_pipeline::modify_pipeline,
+                handle_pipeline::dump_pipeline,
+                handle_pipeline::load_pipeline,
+                handle_pipeline::pipeline_step,
+                handle_pipeline::grammar_cache_stats,
+                handle_pipeline::clear_grammar_cache,
             ]
         )
     }
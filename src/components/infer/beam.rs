@@ -0,0 +1,279 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+use crate::{
+    app::AppState,
+    components::{pipeline::pipeline::Pipeline, state::NamedState, InferenceInterruption},
+};
+
+use super::{
+    logprobs::{logprob, top_k_probs},
+    updates::UpdateSetting,
+};
+
+/// When should the beam search stop before `max_tokens` is reached.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EarlyStopping {
+    /// Stop as soon as there are `beam_width` finished hypotheses.
+    Eager,
+    /// Stop when no live beam can score better than the finished hypotheses.
+    #[default]
+    Heuristic,
+    /// Stop only when all beams are finished.
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BeamSetting {
+    pub beam_width: usize,
+    #[serde(default = "BeamSetting::default_num_return")]
+    pub num_return: usize,
+    #[serde(default = "BeamSetting::default_length_penalty")]
+    pub length_penalty: f32,
+    #[serde(default)]
+    pub early_stopping: EarlyStopping,
+    pub max_tokens: Option<usize>,
+}
+
+impl BeamSetting {
+    fn default_num_return() -> usize {
+        1
+    }
+
+    fn default_length_penalty() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    pub tokens: Vec<u16>,
+    pub logprob: f32,
+    pub score: f32,
+}
+
+impl Hypothesis {
+    fn new(tokens: Vec<u16>, logprob: f32, length_penalty: f32) -> Self {
+        Self {
+            score: logprob / (tokens.len() as f32).powf(length_penalty),
+            tokens,
+            logprob,
+        }
+    }
+}
+
+struct Beam {
+    state: NamedState,
+    tokens: Vec<u16>,
+    logprob: f32,
+    pipeline: Option<Pipeline>,
+}
+
+/// Runs a beam search from the `source` state with the `prompt`.
+///
+/// The source state is forked into beam states, so it's left untouched during
+/// the search. Once the search is done, the beam state of the winning
+/// hypothesis, which holds the prompt and the hypothesis except its last token
+/// (like `infer` does), is copied into the source state.
+///
+/// If a pipeline is given, each beam holds its own copy of it, so the beams
+/// are scored by the transformed and normalized distribution, and are ended by
+/// the terminal or exhaustion. The sampler of the pipeline is not used.
+pub async fn beam_search(
+    state: &AppState,
+    source: String,
+    prompt: Vec<u16>,
+    mut pipeline: Option<Pipeline>,
+    setting: BeamSetting,
+) -> Result<Vec<Hypothesis>> {
+    let BeamSetting {
+        beam_width,
+        num_return,
+        length_penalty,
+        early_stopping,
+        max_tokens,
+    } = setting;
+    let max_concurrency = state.0.config.model.get_max_concurrency();
+    if beam_width == 0 || beam_width > max_concurrency {
+        return Err(Error::msg(format!(
+            "Beam width must be between 1 and {max_concurrency}!"
+        )));
+    }
+    if num_return == 0 || num_return > beam_width {
+        return Err(Error::msg(
+            "Return count must be between 1 and the beam width!",
+        ));
+    }
+    if prompt.is_empty() {
+        return Err(Error::msg("Prompt must not be empty!"));
+    }
+    state
+        .0
+        .states
+        .ensure_writable(std::slice::from_ref(&source))
+        .await?;
+    let max_tokens = max_tokens
+        .unwrap_or(usize::MAX)
+        .min(state.0.config.model.get_max_infer_tokens());
+
+    if let Some(pipeline) = pipeline.as_mut() {
+        let shape = pipeline.get_transformer_shape();
+        if shape.len() != 1 {
+            return Err(Error::msg(
+                "Beam search only supports pipelines with exactly one state slot!",
+            ));
+        }
+        pipeline.update_prompt(
+            &vec![prompt.clone()],
+            UpdateSetting::from_value(&shape, None)?,
+        )?;
+    }
+
+    let root = state.0.states.fork_state(&source, 1).await?.remove(0);
+    let mut logits = state
+        .0
+        .states
+        .create_ticket_from(vec![root.clone()])
+        .await
+        .infer(vec![prompt.clone()])
+        .await;
+    let mut beams = vec![Beam {
+        state: root,
+        tokens: Vec::new(),
+        logprob: 0.0,
+        pipeline,
+    }];
+    // Finished hypotheses keep the state of their parent beam, which is fed
+    // everything but their last token.
+    let mut finished: Vec<(Hypothesis, NamedState)> = Vec::with_capacity(beam_width);
+
+    for step in 0..max_tokens {
+        let mut candidates = Vec::with_capacity(beams.len() * beam_width);
+        for (index, (beam, logits)) in beams.iter().zip(logits).enumerate() {
+            let probs = match &beam.pipeline {
+                Some(pipeline) => pipeline.normalize(vec![logits], state).await,
+                None => state.softmax(vec![logits]).await,
+            }
+            .remove(0);
+            candidates.extend(
+                top_k_probs(&probs, beam_width)
+                    .into_iter()
+                    .map(|(token, prob)| (index, token, beam.logprob + logprob(prob))),
+            );
+        }
+        candidates.sort_unstable_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut next = Vec::with_capacity(beam_width);
+        // Beams whose states are kept by finished hypotheses, so their children
+        // must fork instead of taking them over.
+        let mut kept = vec![false; beams.len()];
+        for (rank, (index, token, score)) in candidates.into_iter().enumerate() {
+            if next.len() >= beam_width {
+                break;
+            }
+            let parent = &beams[index];
+            let mut tokens = parent.tokens.clone();
+            tokens.push(token);
+            let mut pipeline = parent.pipeline.clone();
+            let ended = token == 0
+                || match pipeline.as_mut() {
+                    Some(pipeline) => {
+                        pipeline.terminate(&tokens, tokens.len())?
+                            || match pipeline.update_auto(&vec![vec![token]]) {
+                                Ok(_) => false,
+                                Err(InferenceInterruption::Exhaustion) => true,
+                                Err(InferenceInterruption::Error(e)) => Err(e)?,
+                            }
+                    }
+                    None => false,
+                };
+            if !ended {
+                next.push((index, tokens, score, pipeline));
+            } else if rank < beam_width {
+                kept[index] = true;
+                finished.push((
+                    Hypothesis::new(tokens, score, length_penalty),
+                    parent.state.clone(),
+                ));
+            }
+        }
+        finished.sort_unstable_by(|a, b| b.0.score.total_cmp(&a.0.score));
+        finished.truncate(beam_width);
+
+        if next.is_empty() {
+            break;
+        }
+        if step + 1 == max_tokens {
+            finished.extend(next.into_iter().map(|(index, tokens, score, _)| {
+                (
+                    Hypothesis::new(tokens, score, length_penalty),
+                    beams[index].state.clone(),
+                )
+            }));
+            break;
+        }
+        if finished.len() >= beam_width {
+            let done = match early_stopping {
+                EarlyStopping::Eager => true,
+                EarlyStopping::Heuristic => {
+                    let best_live = next[0].2 / ((step + 1) as f32).powf(length_penalty);
+                    finished
+                        .last()
+                        .is_some_and(|(worst, _)| worst.score >= best_live)
+                }
+                EarlyStopping::Never => false,
+            };
+            if done {
+                break;
+            }
+        }
+
+        // The first child of a beam takes over the state of its parent, others
+        // fork from it before anything is fed.
+        let mut taken = kept;
+        let mut states = Vec::with_capacity(next.len());
+        for (index, ..) in next.iter() {
+            states.push(if taken[*index] {
+                state.0.states.fork_named(&beams[*index].state).await?
+            } else {
+                taken[*index] = true;
+                beams[*index].state.clone()
+            });
+        }
+        let tokens = next
+            .iter()
+            .map(|(_, tokens, ..)| vec![*tokens.last().unwrap()])
+            .collect();
+        logits = state
+            .0
+            .states
+            .create_ticket_from(states.clone())
+            .await
+            .infer(tokens)
+            .await;
+        beams = next
+            .into_iter()
+            .zip(states)
+            .map(|((_, tokens, logprob, pipeline), state)| Beam {
+                state,
+                tokens,
+                logprob,
+                pipeline,
+            })
+            .collect();
+    }
+
+    finished.sort_unstable_by(|a, b| b.0.score.total_cmp(&a.0.score));
+    finished.truncate(num_return);
+
+    let (_, winner) = finished
+        .first()
+        .ok_or(Error::msg("No hypothesis is generated!"))?;
+    state.0.states.overwrite_state(&source, winner).await?;
+
+    Ok(finished
+        .into_iter()
+        .map(|(hypothesis, _)| hypothesis)
+        .collect())
+}
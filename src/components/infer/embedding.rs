@@ -0,0 +1,24 @@
+use serde::Deserialize;
+
+/// Where an embedding is pooled from after the text is fed into a state.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// The state of the last layer.
+    #[default]
+    LastLayer,
+    /// The state of a specified layer.
+    Layer(usize),
+    /// The mean of states of all layers.
+    MeanLayers,
+    /// The logits of the last token.
+    Logits,
+}
+
+pub fn l2_normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
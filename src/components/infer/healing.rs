@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use anyhow::{Error, Result};
+
+use crate::{app::AppState, components::transformer::types::MASKED_LOGIT};
+
+/// Token healing at the boundary of the prompt and the generation.
+///
+/// The last token of the prompt is removed before feeding the states, and its
+/// bytes become a prefix the first generated tokens must follow, so the model
+/// picks the tokenization of the boundary itself.
+#[derive(Debug, Clone)]
+pub struct TokenHealing {
+    /// Bytes of the removed token.
+    prefix: Vec<u8>,
+    /// Tokens which may follow some suffix of the prefix, along with their bytes.
+    candidates: Arc<Vec<(u16, Vec<u8>)>>,
+}
+
+/// Progress of the healing during a generation.
+#[derive(Debug, Clone)]
+pub struct HealingState<'a> {
+    healing: &'a TokenHealing,
+    /// Bytes of the prefix not generated yet.
+    offset: usize,
+}
+
+impl TokenHealing {
+    /// Removes the last token of each prompt. All prompts must end with a same
+    /// token, since the generated tokens are shared by all states. Returns
+    /// `None` without touching the prompts if any of them is too short.
+    pub fn new(state: &AppState, tokens: &mut [Vec<u16>]) -> Result<Option<Self>> {
+        if tokens.is_empty() || tokens.iter().any(|x| x.len() < 2) {
+            return Ok(None);
+        }
+        let last = *tokens[0].last().unwrap();
+        if tokens.iter().any(|x| x.last() != Some(&last)) {
+            return Err(Error::msg(
+                "Token healing requires all prompts to end with a same token!",
+            ));
+        }
+        let prefix = state.0.tokenizer.decode(&[last])?;
+        if prefix.is_empty() {
+            return Ok(None);
+        }
+        for prompt in tokens.iter_mut() {
+            prompt.pop();
+        }
+
+        let candidates = state
+            .0
+            .tokenizer
+            .bytes_to_token_index()
+            .iter()
+            .filter(|(bytes, _)| (0..prefix.len()).any(|x| compatible(&prefix[x..], bytes)))
+            .map(|(bytes, token)| (*token, bytes.clone()))
+            .collect();
+        Ok(Some(Self {
+            prefix,
+            candidates: Arc::new(candidates),
+        }))
+    }
+
+    pub fn start(&self) -> HealingState<'_> {
+        HealingState {
+            healing: self,
+            offset: 0,
+        }
+    }
+
+    /// Removes the healed prefix from the decoded generation, so it continues
+    /// the prompt as sent by the client. The generation may end before the
+    /// prefix is complete, in which case only the generated part is removed.
+    pub fn reconcile(&self, decoded: &[u8]) -> Vec<u8> {
+        let healed = decoded
+            .iter()
+            .zip(self.prefix.iter())
+            .take_while(|(x, y)| x == y)
+            .count();
+        decoded[healed..].to_vec()
+    }
+}
+
+impl HealingState<'_> {
+    pub fn is_done(&self) -> bool {
+        self.offset >= self.healing.prefix.len()
+    }
+
+    /// Masks the tokens which don't follow the rest of the prefix, for all states.
+    pub fn mask(&self, logits: &mut [Vec<f32>]) {
+        let remaining = &self.healing.prefix[self.offset..];
+        let mut allowed = vec![false; logits.first().map_or(0, |x| x.len())];
+        for (token, bytes) in self.healing.candidates.iter() {
+            if let Some(allowed) = allowed.get_mut(*token as usize) {
+                *allowed = compatible(remaining, bytes);
+            }
+        }
+        for logits in logits.iter_mut() {
+            for (logit, allowed) in logits.iter_mut().zip(allowed.iter()) {
+                if !allowed {
+                    *logit = MASKED_LOGIT;
+                }
+            }
+        }
+    }
+
+    /// Moves past the bytes of a generated token.
+    pub fn advance(&mut self, state: &AppState, token: u16) -> Result<()> {
+        let bytes = state.0.tokenizer.decode(&[token])?;
+        if compatible(&self.healing.prefix[self.offset..], &bytes) {
+            self.offset += bytes.len();
+        } else {
+            // Only possible if every token is masked, so the healing is given up.
+            self.offset = self.healing.prefix.len();
+        }
+        Ok(())
+    }
+}
+
+/// Whether a token either completes the remaining prefix or is a part of it.
+fn compatible(remaining: &[u8], bytes: &[u8]) -> bool {
+    !bytes.is_empty() && (bytes.starts_with(remaining) || remaining.starts_with(bytes))
+}
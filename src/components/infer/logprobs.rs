@@ -0,0 +1,68 @@
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::app::AppState;
+
+/// A token along with its log-probability under the sampled distribution.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprob {
+    pub token: u16,
+    pub bytes: Vec<u8>,
+    pub logprob: f32,
+}
+
+/// Log-probability record of a sampled token, with the top-k alternatives
+/// from the same distribution.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprobs {
+    #[serde(flatten)]
+    pub sampled: TokenLogprob,
+    pub top: Vec<TokenLogprob>,
+}
+
+impl TokenLogprob {
+    pub fn new(state: &AppState, token: u16, prob: f32) -> Result<Self> {
+        Ok(Self {
+            token,
+            bytes: state.0.tokenizer.decode(&[token])?,
+            logprob: logprob(prob),
+        })
+    }
+}
+
+impl TokenLogprobs {
+    /// Records the sampled token and the `top_k` most probable tokens from `probs`.
+    pub fn new(state: &AppState, probs: &[f32], token: u16, top_k: usize) -> Result<Self> {
+        Ok(Self {
+            sampled: TokenLogprob::new(state, token, probs[token as usize])?,
+            top: top_k_probs(probs, top_k)
+                .into_iter()
+                .map(|(token, prob)| TokenLogprob::new(state, token, prob))
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+}
+
+/// Natural log of a probability, clamped so that impossible tokens
+/// still produce a finite (and JSON-serializable) value.
+#[inline]
+pub fn logprob(prob: f32) -> f32 {
+    prob.max(f32::MIN_POSITIVE).ln()
+}
+
+/// Returns the `k` most probable tokens in descending order of probability.
+pub fn top_k_probs(probs: &[f32], k: usize) -> Vec<(u16, f32)> {
+    let k = k.min(probs.len());
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut indices = (0..probs.len()).collect_vec();
+    indices.select_nth_unstable_by(k - 1, |&a, &b| probs[b].total_cmp(&probs[a]));
+    indices.truncate(k);
+    indices.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+    indices
+        .into_iter()
+        .map(|index| (index as u16, probs[index]))
+        .collect()
+}
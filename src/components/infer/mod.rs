@@ -1,2 +1,7 @@
+pub mod beam;
+pub mod embedding;
+pub mod healing;
+pub mod logprobs;
 pub mod tokens;
+pub mod trace;
 pub mod updates;
@@ -0,0 +1,91 @@
+use serde::Serialize;
+
+use crate::components::transformer::types::MASKED_LOGIT;
+
+use super::logprobs::top_k_probs;
+
+/// Count of top tokens recorded for each distribution in a trace.
+pub const TRACE_TOP_K: usize = 8;
+/// Max tokens listed in a token set of a trace, the count is always complete.
+pub const TRACE_MAX_LISTED: usize = 64;
+/// Logits at or below this are considered masked, like the ones set by
+/// `bnf_grammar` or `disable_token`, even if a later transformer moved them a
+/// bit.
+const MASKED_THRESHOLD: f32 = MASKED_LOGIT / 10.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TracedToken {
+    pub token: u16,
+    pub value: f32,
+}
+
+/// A set of tokens, only the first `TRACE_MAX_LISTED` are listed.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenSet {
+    pub count: usize,
+    pub tokens: Vec<u16>,
+}
+
+impl TokenSet {
+    pub fn new(tokens: impl Iterator<Item = u16>) -> Self {
+        let mut count = 0;
+        let mut listed = Vec::new();
+        for token in tokens {
+            if count < TRACE_MAX_LISTED {
+                listed.push(token);
+            }
+            count += 1;
+        }
+        Self {
+            count,
+            tokens: listed,
+        }
+    }
+}
+
+/// The logits right after a transformer.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransformerTrace {
+    pub top: Vec<TracedToken>,
+    /// Tokens masked by this transformer, which were not masked before.
+    pub masked: TokenSet,
+}
+
+impl TransformerTrace {
+    /// Records the logits after a transformer, `masked` holds whether each token
+    /// was masked before and is updated.
+    pub fn new(logits: &[f32], masked: &mut [bool]) -> Self {
+        let newly_masked =
+            TokenSet::new(logits.iter().zip(masked.iter_mut()).enumerate().filter_map(
+                |(token, (&logit, masked))| {
+                    (!*masked && logit <= MASKED_THRESHOLD).then(|| {
+                        *masked = true;
+                        token as u16
+                    })
+                },
+            ));
+        Self {
+            top: top_values(logits),
+            masked: newly_masked,
+        }
+    }
+}
+
+/// Everything happened in a single step of a generation.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTrace {
+    /// Traces of transformers, in order, for each state slot.
+    pub transformers: Vec<Vec<TransformerTrace>>,
+    /// Top of the distribution fed into the sampler.
+    pub normalized: Vec<TracedToken>,
+    /// Tokens the sampler sampled from, if the sampler has a cutoff.
+    pub candidates: Option<TokenSet>,
+    pub token: u16,
+}
+
+pub fn top_values(values: &[f32]) -> Vec<TracedToken> {
+    top_k_probs(values, TRACE_TOP_K)
+        .into_iter()
+        .map(|(token, value)| TracedToken { token, value })
+        .collect()
+}
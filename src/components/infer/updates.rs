@@ -67,6 +67,7 @@ impl ResetSetting {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct UpdateSetting {
     pub transformers: Vec<Vec<bool>>,
     pub sampler: bool,
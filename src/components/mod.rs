@@ -1,17 +1,18 @@
 use std::collections::HashMap;
 
 use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{app::AppState, hashmap_ex};
 
 use self::{
-    normalizer::{classifier_free_guidance, types::Normalizer},
-    sampler::{nucleus, types::Sampler, typical},
+    normalizer::{classifier_free_guidance, contrastive, types::Normalizer},
+    sampler::{chain, mirostat, nucleus, types::Sampler, typical},
     terminal::{lengthed, types::Terminal, until},
     transformer::{
-        bnf_constraint, disable_tokens, global_penalty, logits_compressor, sliding_penalty,
-        types::Transformer,
+        ban_phrases, bnf_constraint, disable_tokens, dry, global_penalty, json_schema, logit_bias,
+        logits_compressor, regex, sliding_penalty, types::Transformer, watermark,
     },
 };
 
@@ -39,6 +40,31 @@ impl InferenceInterruption {
     }
 }
 
+/// A dumped component, which can be restored by constructing it from `params`
+/// with the constructor registered as `type_id`, then restoring its internal
+/// `state`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentDump {
+    pub type_id: String,
+    pub params: Option<Value>,
+    pub state: Option<Value>,
+}
+
+impl ComponentDump {
+    pub fn new<P: Serialize>(type_id: &str, params: &P) -> Result<Self> {
+        Ok(Self {
+            type_id: type_id.to_string(),
+            params: Some(serde_json::to_value(params)?),
+            state: None,
+        })
+    }
+
+    pub fn with_state<S: Serialize>(mut self, state: &S) -> Result<Self> {
+        self.state = Some(serde_json::to_value(state)?);
+        Ok(self)
+    }
+}
+
 pub struct Registry {
     terminal: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Terminal>>>,
     transformer: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Transformer>>>,
@@ -63,20 +89,29 @@ impl Registry {
                         "sliding_penalty" => sliding_penalty::initialize_sliding,
                         "disable_token" => disable_tokens::initialize_disable,
                         "bnf_grammar" => bnf_constraint::BNFConstraint::initialize,
-                        "logits_compressor"=>logits_compressor::LogitsCompressor::initialize
+                        "logits_compressor"=>logits_compressor::LogitsCompressor::initialize,
+                        "dry" => dry::Dry::initialize,
+                        "logit_bias" => logit_bias::LogitBias::initialize,
+                        "ban_phrases" => ban_phrases::BanPhrases::initialize,
+                        "regex" => regex::RegexConstraint::initialize,
+                        "json_schema" => json_schema::JsonSchemaConstraint::initialize,
+                        "watermark" => watermark::Watermark::initialize
                     }
             },
             sampler: hashmap_ex! {
                 HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Sampler>>>,
                     {
                         "nucleus" => nucleus::initialize,
-                        "typical" => typical::TypicalSampler::initialize
+                        "typical" => typical::TypicalSampler::initialize,
+                        "mirostat" => mirostat::MirostatSampler::initialize,
+                        "chain" => chain::ChainSampler::initialize
                     }
             },
             normalizer: hashmap_ex! {
                 HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Normalizer>>>,
                     {
                         "classifier_free_guidance" => classifier_free_guidance::ClassifierFreeGuidance::initialize,
+                        "contrastive" => contrastive::Contrastive::initialize,
                     }
             },
         }
@@ -137,4 +172,52 @@ impl Registry {
             Err(Error::msg("Normalizer not found!"))
         }
     }
+
+    pub fn restore_terminal(
+        &self,
+        state: AppState,
+        dump: ComponentDump,
+    ) -> Result<Box<dyn Terminal>> {
+        let mut terminal = self.create_terminal(&dump.type_id, state, dump.params)?;
+        if let Some(internal) = dump.state {
+            terminal.restore(internal)?;
+        }
+        Ok(terminal)
+    }
+
+    pub fn restore_sampler(
+        &self,
+        state: AppState,
+        dump: ComponentDump,
+    ) -> Result<Box<dyn Sampler>> {
+        let mut sampler = self.create_sampler(&dump.type_id, state, dump.params)?;
+        if let Some(internal) = dump.state {
+            sampler.restore(internal)?;
+        }
+        Ok(sampler)
+    }
+
+    pub fn restore_transformer(
+        &self,
+        state: AppState,
+        dump: ComponentDump,
+    ) -> Result<Box<dyn Transformer>> {
+        let mut transformer = self.create_transformer(&dump.type_id, state, dump.params)?;
+        if let Some(internal) = dump.state {
+            transformer.restore(internal)?;
+        }
+        Ok(transformer)
+    }
+
+    pub fn restore_normalizer(
+        &self,
+        state: AppState,
+        dump: ComponentDump,
+    ) -> Result<Box<dyn Normalizer>> {
+        let mut normalizer = self.create_normalizer(&dump.type_id, state, dump.params)?;
+        if let Some(internal) = dump.state {
+            normalizer.restore(internal)?;
+        }
+        Ok(normalizer)
+    }
 }
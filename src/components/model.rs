@@ -4,8 +4,8 @@ use itertools::Itertools;
 use web_rwkv::{
     context::Context,
     model::{
-        run::ModelRun, softmax::ModelSoftmax, v4, v5, Build, ModelBase, ModelInfo, ModelInput,
-        ModelOutput, ModelState, StateBuilder,
+        run::ModelRun, softmax::ModelSoftmax, v4, v5, BackedState, Build, ModelBase, ModelInfo,
+        ModelInput, ModelOutput, ModelState, StateBuilder,
     },
 };
 
@@ -111,6 +111,14 @@ impl AxumBackedState {
         }
     }
 
+    /// Extracts the embedding of a layer from the state.
+    pub fn embed(&self, layer: usize) -> Vec<f32> {
+        match self {
+            AxumBackedState::V4(state) => state.embed(0, layer),
+            AxumBackedState::V5(state) => state.embed(0, layer),
+        }
+    }
+
     pub async fn back_from(dst: &AxumModelState, dst_index: usize) -> Result<AxumBackedState> {
         match dst {
             AxumModelState::V4(dst) => Ok(AxumBackedState::V4(dst.back_batch(dst_index).await?)),
@@ -3,24 +3,24 @@ use ndarray::Array1;
 use rayon::iter::{
     IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{app::AppState, components::ComponentDump};
 
 use super::types::Normalizer;
 
 const MAIN_STATE_INDEX: usize = 0; // assume the first state is the main state
 const DYNAMIC_GAMMA_INDEX: usize = 1; // assume only the second state can be dynamic
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DynamicGamma {
     min: f32,
     max: f32,
     threshold: f32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ClassifierFreeGuidanceData {
     static_gammas: Vec<f32>,
     #[serde(default)]
@@ -111,4 +111,8 @@ impl Normalizer for ClassifierFreeGuidance {
             state: self.state.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("classifier_free_guidance", &self.cfg_data)
+    }
 }
@@ -0,0 +1,103 @@
+use anyhow::{Error, Result};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{app::AppState, components::ComponentDump};
+
+use super::types::Normalizer;
+
+const MAIN_STATE_INDEX: usize = 0; // assume the first state is the main (expert) state
+const AMATEUR_STATE_INDEX: usize = 1; // assume the second state is the amateur state
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContrastiveData {
+    /// Only tokens with a main state prob of at least `alpha` times the max
+    /// one are plausible.
+    #[serde(default = "ContrastiveData::default_alpha")]
+    alpha: f32,
+    /// Scaling of the log-prob difference.
+    #[serde(default = "ContrastiveData::default_beta")]
+    beta: f32,
+}
+
+impl ContrastiveData {
+    fn default_alpha() -> f32 {
+        0.1
+    }
+
+    fn default_beta() -> f32 {
+        0.5
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Contrastive {
+    data: ContrastiveData,
+    state: AppState,
+}
+
+impl Contrastive {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Normalizer>> {
+        // All params are optional, so the payload can be omitted.
+        let data = serde_json::from_value::<ContrastiveData>(
+            data.unwrap_or_else(|| Value::Object(Default::default())),
+        )?;
+        if !(data.alpha > 0.0 && data.alpha <= 1.0) {
+            return Err(Error::msg("alpha must be in (0, 1]!"));
+        }
+        if !(data.beta >= 0.0 && data.beta.is_finite()) {
+            return Err(Error::msg("beta must be finite and not less than 0!"));
+        }
+        Ok(Box::new(Contrastive { data, state }))
+    }
+}
+
+impl Normalizer for Contrastive {
+    fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        let mut probs = self.state.softmax_blocking(logits);
+        if probs.len() <= AMATEUR_STATE_INDEX {
+            return probs;
+        }
+
+        let main = &probs[MAIN_STATE_INDEX];
+        let amateur = &probs[AMATEUR_STATE_INDEX];
+        let threshold = self.data.alpha * main.par_iter().cloned().reduce(|| 0.0, f32::max);
+        // score = log p_main + beta * (log p_main - log p_amateur), only for plausible
+        // tokens. Probs are clamped so the logs are finite.
+        let beta = self.data.beta;
+        let scores: Vec<Option<f32>> = main
+            .par_iter()
+            .zip(amateur.par_iter())
+            .map(|(&p, &q)| {
+                (p >= threshold).then(|| {
+                    let (p, q) = (p.max(f32::MIN_POSITIVE).ln(), q.max(f32::MIN_POSITIVE).ln());
+                    p + beta * (p - q)
+                })
+            })
+            .collect();
+        let max_score = scores
+            .iter()
+            .flatten()
+            .cloned()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let exps: Vec<f32> = scores
+            .par_iter()
+            .map(|x| x.map_or(0.0, |x| (x - max_score).exp()))
+            .collect();
+        let sum: f32 = exps.iter().sum();
+        probs[MAIN_STATE_INDEX] = exps.into_iter().map(|x| x / sum).collect();
+        probs
+    }
+
+    fn clone(&self) -> Box<dyn Normalizer> {
+        Box::new(Contrastive {
+            data: self.data.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("contrastive", &self.data)
+    }
+}
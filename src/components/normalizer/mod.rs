@@ -1,2 +1,3 @@
 pub mod classifier_free_guidance;
+pub mod contrastive;
 pub mod types;
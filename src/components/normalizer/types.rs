@@ -1,5 +1,6 @@
-use crate::components::InferenceInterruption;
+use crate::components::{ComponentDump, InferenceInterruption};
 use anyhow::{Error, Result};
+use serde_json::Value;
 use std::fmt::Debug;
 
 pub trait Normalizer: Send + Sync + Debug {
@@ -41,6 +42,19 @@ pub trait Normalizer: Send + Sync + Debug {
     /// that the state mutated in `update` will not mutate the cloned state, it is safe to
     /// share internal state by using `Arc`, etc.
     fn clone(&self) -> Box<dyn Normalizer>;
+    /// Dumps the construction params along with the internal state of the `Normalizer`,
+    /// so it can be persisted and restored later.
+    ///
+    /// The `type_id` of the dump must be the one the `Normalizer` is registered with.
+    fn dump(&self) -> Result<ComponentDump>;
+    /// Restores the internal state from a dump, after the `Normalizer` is constructed
+    /// from the dumped params.
+    ///
+    /// By default the `Normalizer` has no internal state, so nothing is restored.
+    #[allow(unused_variables)]
+    fn restore(&mut self, state: Value) -> Result<()> {
+        Ok(())
+    }
     /// Implements a special update for prompt, where it will not throw InferenceInterruption::Exhaustion
     /// if the exhaustion happened. This is for special components like BNF which might exhaust when
     /// reading prompt.
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::fs::{self, File};
+use tokio::io::*;
+
+use crate::{app::AppState, components::ComponentDump};
+
+use super::pipeline::Pipeline;
+
+#[derive(Deserialize, Serialize)]
+struct PipelineRepr {
+    transformers: Vec<Vec<ComponentDump>>,
+    sampler: ComponentDump,
+    terminal: ComponentDump,
+    normalizer: Option<ComponentDump>,
+}
+
+impl PipelineRepr {
+    pub fn new(pipeline: &Pipeline) -> Result<Self> {
+        Ok(Self {
+            transformers: pipeline
+                .transformers
+                .iter()
+                .map(|x| x.iter().map(|x| x.dump()).collect::<Result<Vec<_>>>())
+                .collect::<Result<Vec<_>>>()?,
+            sampler: pipeline.sampler.dump()?,
+            terminal: pipeline.terminal.dump()?,
+            normalizer: pipeline.normalizer.as_ref().map(|x| x.dump()).transpose()?,
+        })
+    }
+
+    pub fn into_pipeline(self, state: &AppState) -> Result<Pipeline> {
+        let registry = &state.0.registry;
+        Ok(Pipeline::new(
+            self.transformers
+                .into_iter()
+                .map(|x| {
+                    x.into_iter()
+                        .map(|x| registry.restore_transformer(state.clone(), x))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .collect::<Result<Vec<_>>>()?,
+            registry.restore_sampler(state.clone(), self.sampler)?,
+            registry.restore_terminal(state.clone(), self.terminal)?,
+            self.normalizer
+                .map(|x| registry.restore_normalizer(state.clone(), x))
+                .transpose()?,
+        ))
+    }
+}
+
+pub async fn dump_pipeline(pipeline: &Pipeline, path: PathBuf) -> Result<()> {
+    let repr = PipelineRepr::new(pipeline)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut file = File::create(path).await?;
+    file.write_all(&serde_cbor::to_vec(&repr)?).await?;
+    Ok(())
+}
+
+pub async fn load_pipeline(state: &AppState, path: PathBuf) -> Result<Pipeline> {
+    let mut buf = Vec::with_capacity(1024 * 64);
+    File::open(path).await?.read_to_end(&mut buf).await?;
+    serde_cbor::from_slice::<PipelineRepr>(&buf)?.into_pipeline(state)
+}
@@ -1,6 +1,10 @@
 use anyhow::{Error, Result};
 use rayon::prelude::*;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
 use tokio::sync::{Mutex, RwLock};
 
 use serde::Deserialize;
@@ -15,6 +19,7 @@ use super::{
     InferenceInterruption::{self},
 };
 
+mod dump;
 pub mod mutate;
 pub mod pipeline;
 
@@ -24,9 +29,19 @@ pub struct IdParam {
     params: Option<Value>,
 }
 
+/// What to do with the copy of a pipeline after a forked inference.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForkPolicy {
+    /// Leave the stored pipeline untouched.
+    Discard,
+    /// Replace the stored pipeline with the copy.
+    Overwrite,
+}
+
+/// Components of a pipeline, used to build a stored or an inline pipeline.
 #[derive(Debug, Deserialize)]
-struct PipelinePayload {
-    id: String,
+pub struct PipelineSpec {
     transformers: Vec<Vec<IdParam>>,
     sampler: IdParam,
     terminal: IdParam,
@@ -34,30 +49,81 @@ struct PipelinePayload {
     initial_prompt: Option<Vec<Value>>,
 }
 
+#[derive(Debug, Deserialize)]
+struct PipelinePayload {
+    id: String,
+    #[serde(flatten)]
+    spec: PipelineSpec,
+}
+
 pub struct Pipelines {
     map: RwLock<HashMap<String, Arc<Mutex<Pipeline>>>>,
+    readonly: RwLock<HashSet<String>>,
 }
 
 impl Pipelines {
     pub fn new() -> Self {
         Self {
             map: RwLock::new(HashMap::with_capacity(128)),
+            readonly: RwLock::new(HashSet::new()),
         }
     }
 
-    pub async fn create_pipeline(&self, state: &AppState, payload: Value) -> Result<()> {
-        let PipelinePayload {
-            id,
+    pub async fn create_pipeline(&self, state: &AppState, payload: Value) -> Result<String> {
+        let PipelinePayload { id, spec } = serde_json::from_value(payload)?;
+
+        if self.has_pipeline(&id).await {
+            return Err(Error::msg("Pipeline id exists!"));
+        }
+
+        self.set_pipeline(&id, Self::build_pipeline(state, spec)?)
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Resolves a pipeline from either a pipeline id, or an inline pipeline spec.
+    ///
+    /// Inline pipelines are built for the caller only, so they never contend with
+    /// other requests.
+    pub async fn resolve_pipeline(
+        &self,
+        state: &AppState,
+        pipeline: Value,
+    ) -> Result<Arc<Mutex<Pipeline>>> {
+        match pipeline {
+            Value::String(id) => self.get_pipeline(&id).await,
+            Value::Object(_) => Ok(Arc::new(Mutex::new(Self::build_pipeline(
+                state,
+                serde_json::from_value(pipeline)?,
+            )?))),
+            _ => Err(Error::msg(
+                "Pipeline must be either a pipeline id or a pipeline spec!",
+            )),
+        }
+    }
+
+    /// Like `resolve_pipeline`, but the pipeline will be mutated, so read-only
+    /// pipelines are rejected.
+    pub async fn resolve_pipeline_mut(
+        &self,
+        state: &AppState,
+        pipeline: Value,
+    ) -> Result<Arc<Mutex<Pipeline>>> {
+        if let Value::String(id) = &pipeline {
+            self.ensure_writable(id).await?;
+        }
+        self.resolve_pipeline(state, pipeline).await
+    }
+
+    pub fn build_pipeline(state: &AppState, spec: PipelineSpec) -> Result<Pipeline> {
+        let PipelineSpec {
             transformers,
             sampler,
             terminal,
             normalizer,
             initial_prompt,
-        } = serde_json::from_value(payload)?;
-
-        if self.has_pipeline(&id).await {
-            return Err(Error::msg("Pipeline id exists!"));
-        }
+        } = spec;
 
         let initial_prompt = initial_prompt.map(|s| {
             s.into_iter()
@@ -127,16 +193,25 @@ impl Pipelines {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        self.set_pipeline(
-            &id,
-            Pipeline::new(transformers, sampler, terminal, normalizer),
-        )
-        .await?;
+        Ok(Pipeline::new(transformers, sampler, terminal, normalizer))
+    }
 
-        Ok(())
+    pub async fn dump_pipeline(&self, id: &str, path: PathBuf) -> Result<()> {
+        let pipeline = self.get_pipeline(id).await?;
+        let pipeline = pipeline.lock().await;
+        dump::dump_pipeline(&pipeline, path).await
+    }
+
+    pub async fn load_pipeline(&self, state: &AppState, id: &str, path: PathBuf) -> Result<()> {
+        if self.has_pipeline(id).await {
+            return Err(Error::msg("Pipeline id exists!"));
+        }
+        self.set_pipeline(id, dump::load_pipeline(state, path).await?)
+            .await
     }
 
     pub async fn remove_pipeline(&self, id: &str) -> Result<()> {
+        self.ensure_writable(id).await?;
         self.map
             .write()
             .await
@@ -146,6 +221,7 @@ impl Pipelines {
     }
 
     pub async fn pop_pipeline(&self, id: &str) -> Result<Pipeline> {
+        self.ensure_writable(id).await?;
         Ok(Arc::try_unwrap(
             self.map
                 .write()
@@ -171,6 +247,28 @@ impl Pipelines {
             .clone())
     }
 
+    /// Gets a pipeline which will be mutated, read-only pipelines are rejected.
+    pub async fn get_pipeline_mut(&self, id: &str) -> Result<Arc<Mutex<Pipeline>>> {
+        self.ensure_writable(id).await?;
+        self.get_pipeline(id).await
+    }
+
+    /// Marks a pipeline as read-only, so it can only be copied or inferred with
+    /// a fork.
+    pub async fn mark_readonly(&self, id: &str) {
+        self.readonly.write().await.insert(id.to_string());
+    }
+
+    pub async fn ensure_writable(&self, id: &str) -> Result<()> {
+        if self.readonly.read().await.contains(id) {
+            Err(Error::msg(format!(
+                "Pipeline {id} is read-only, copy it or infer with a fork instead!"
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
     pub async fn set_pipeline(&self, id: &str, pipeline: Pipeline) -> Result<()> {
         if self.has_pipeline(id).await {
             return Err(Error::msg("Pipeline ID exists."));
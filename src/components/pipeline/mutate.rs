@@ -2,7 +2,13 @@ use anyhow::{Error, Result};
 use serde::Deserialize;
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{
+    app::AppState,
+    components::{
+        normalizer::types::Normalizer, sampler::types::Sampler, terminal::types::Terminal,
+        transformer::types::Transformer,
+    },
+};
 
 use super::{pipeline::Pipeline, IdParam};
 
@@ -14,75 +20,421 @@ pub struct ReplaceTransformer {
     transformer_index: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InsertTransformer {
+    type_id: String,
+    params: Option<Value>,
+    state_index: usize,
+    /// Appends to the chain if omitted.
+    transformer_index: Option<usize>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DeleteTransformer {
     state_index: usize,
     transformer_index: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct MoveTransformer {
+    state_index: usize,
+    from: usize,
+    to: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddStateSlot {
+    /// Appends to the slots if omitted.
+    state_index: Option<usize>,
+    #[serde(default)]
+    transformers: Vec<IdParam>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveStateSlot {
+    state_index: usize,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "modification", rename_all = "snake_case")]
 pub enum Modification {
     ReplaceTransformer(ReplaceTransformer),
+    InsertTransformer(InsertTransformer),
+    DeleteTransformer(DeleteTransformer),
+    MoveTransformer(MoveTransformer),
     ReplaceSampler(IdParam),
     ReplaceTerminal(IdParam),
+    ReplaceNormalizer(IdParam),
+    RemoveNormalizer,
+    AddStateSlot(AddStateSlot),
+    RemoveStateSlot(RemoveStateSlot),
+}
+
+fn get_slot(pipeline: &mut Pipeline, state_index: usize) -> Result<&mut Vec<Box<dyn Transformer>>> {
+    let slot_count = pipeline.transformers.len();
+    pipeline
+        .transformers
+        .get_mut(state_index)
+        .ok_or(Error::msg(format!(
+            "State slot {state_index} does not exist, there are {slot_count} slots!"
+        )))
+}
+
+fn check_transformer_index(slot: &[Box<dyn Transformer>], index: usize) -> Result<()> {
+    if index < slot.len() {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "Transformer {index} does not exist, there are {} transformers in the slot!",
+            slot.len()
+        )))
+    }
+}
+
+/// A modification whose components are already created, so applying it only
+/// fails on invalid indices.
+enum Change {
+    ReplaceTransformer {
+        transformer: Box<dyn Transformer>,
+        state_index: usize,
+        transformer_index: usize,
+    },
+    InsertTransformer {
+        transformer: Box<dyn Transformer>,
+        state_index: usize,
+        transformer_index: Option<usize>,
+    },
     DeleteTransformer(DeleteTransformer),
+    MoveTransformer(MoveTransformer),
+    ReplaceSampler(Box<dyn Sampler>),
+    ReplaceTerminal(Box<dyn Terminal>),
+    ReplaceNormalizer(Box<dyn Normalizer>),
+    RemoveNormalizer,
+    AddStateSlot {
+        state_index: Option<usize>,
+        transformers: Vec<Box<dyn Transformer>>,
+    },
+    RemoveStateSlot(RemoveStateSlot),
 }
 
 impl Modification {
-    pub fn modify(self, pipeline: &mut Pipeline, state: &AppState) -> Result<()> {
-        match self {
+    /// Applies all modifications to the pipeline. All components are created
+    /// first, then the modifications are applied to a copy in order, so either
+    /// all of them are applied or none of them are.
+    pub fn modify_all(
+        modifications: Vec<Modification>,
+        pipeline: &mut Pipeline,
+        state: &AppState,
+    ) -> Result<()> {
+        let changes = modifications
+            .into_iter()
+            .enumerate()
+            .map(|(index, modification)| {
+                modification
+                    .create(state)
+                    .map_err(|e| Error::msg(format!("Modification {index} failed: {e}")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Change::apply_all(changes, pipeline)
+    }
+
+    /// Creates the components of the modification.
+    fn create(self, state: &AppState) -> Result<Change> {
+        let registry = &state.0.registry;
+        let create_transformer =
+            |type_id: &str, params| registry.create_transformer(type_id, state.clone(), params);
+        Ok(match self {
             Modification::ReplaceTransformer(ReplaceTransformer {
                 type_id,
                 params,
                 state_index,
                 transformer_index,
-            }) => {
-                let to_be_modified = pipeline
-                    .transformers
-                    .get_mut(state_index)
-                    .ok_or(Error::msg("State slot does not exist!"))?;
-
-                let transformer =
-                    state
-                        .0
-                        .registry
-                        .create_transformer(&type_id, state.clone(), params)?;
-                if to_be_modified.len() >= transformer_index {
-                    to_be_modified[transformer_index] = transformer;
-                } else {
-                    to_be_modified.push(transformer)
-                }
+            }) => Change::ReplaceTransformer {
+                transformer: create_transformer(&type_id, params)?,
+                state_index,
+                transformer_index,
+            },
+            Modification::InsertTransformer(InsertTransformer {
+                type_id,
+                params,
+                state_index,
+                transformer_index,
+            }) => Change::InsertTransformer {
+                transformer: create_transformer(&type_id, params)?,
+                state_index,
+                transformer_index,
+            },
+            Modification::DeleteTransformer(modification) => {
+                Change::DeleteTransformer(modification)
             }
+            Modification::MoveTransformer(modification) => Change::MoveTransformer(modification),
             Modification::ReplaceSampler(IdParam { type_id, params }) => {
-                let sampler = state
-                    .0
-                    .registry
-                    .create_sampler(&type_id, state.clone(), params)?;
-                pipeline.sampler = sampler;
+                Change::ReplaceSampler(registry.create_sampler(&type_id, state.clone(), params)?)
             }
-            Modification::ReplaceTerminal(IdParam { type_id, params }) => {
-                let terminal = state
-                    .0
-                    .registry
-                    .create_terminal(&type_id, state.clone(), params)?;
-                pipeline.terminal = terminal;
+            Modification::ReplaceTerminal(IdParam { type_id, params }) => Change::ReplaceTerminal(
+                registry.create_terminal(&type_id, state.clone(), params)?,
+            ),
+            Modification::ReplaceNormalizer(IdParam { type_id, params }) => {
+                Change::ReplaceNormalizer(registry.create_normalizer(
+                    &type_id,
+                    state.clone(),
+                    params,
+                )?)
             }
-            Modification::DeleteTransformer(DeleteTransformer {
+            Modification::RemoveNormalizer => Change::RemoveNormalizer,
+            Modification::AddStateSlot(AddStateSlot {
+                state_index,
+                transformers,
+            }) => Change::AddStateSlot {
+                state_index,
+                transformers: transformers
+                    .into_iter()
+                    .map(|IdParam { type_id, params }| create_transformer(&type_id, params))
+                    .collect::<Result<Vec<_>>>()?,
+            },
+            Modification::RemoveStateSlot(modification) => Change::RemoveStateSlot(modification),
+        })
+    }
+}
+
+impl Change {
+    /// Applies the changes to a copy of the pipeline in order, which replaces
+    /// the pipeline only if all of them succeed.
+    fn apply_all(changes: Vec<Change>, pipeline: &mut Pipeline) -> Result<()> {
+        let mut modified = pipeline.clone();
+        for (index, change) in changes.into_iter().enumerate() {
+            change
+                .apply(&mut modified)
+                .map_err(|e| Error::msg(format!("Modification {index} failed: {e}")))?;
+        }
+        *pipeline = modified;
+        Ok(())
+    }
+
+    fn apply(self, pipeline: &mut Pipeline) -> Result<()> {
+        match self {
+            Change::ReplaceTransformer {
+                transformer,
+                state_index,
+                transformer_index,
+            } => {
+                let to_be_modified = get_slot(pipeline, state_index)?;
+                check_transformer_index(to_be_modified, transformer_index)?;
+                to_be_modified[transformer_index] = transformer;
+            }
+            Change::InsertTransformer {
+                transformer,
+                state_index,
+                transformer_index,
+            } => {
+                let to_be_modified = get_slot(pipeline, state_index)?;
+                let transformer_index = transformer_index.unwrap_or(to_be_modified.len());
+                if transformer_index > to_be_modified.len() {
+                    return Err(Error::msg(format!(
+                        "Can not insert at {transformer_index}, there are {} transformers in the slot!",
+                        to_be_modified.len()
+                    )));
+                }
+                to_be_modified.insert(transformer_index, transformer);
+            }
+            Change::DeleteTransformer(DeleteTransformer {
                 state_index,
                 transformer_index,
             }) => {
-                let to_be_removed = pipeline
-                    .transformers
-                    .get_mut(state_index)
-                    .ok_or(Error::msg("State slot does not exist!"))?;
-                if to_be_removed.len() >= transformer_index {
-                    to_be_removed.remove(transformer_index);
-                } else {
-                    to_be_removed.pop();
+                let to_be_removed = get_slot(pipeline, state_index)?;
+                check_transformer_index(to_be_removed, transformer_index)?;
+                to_be_removed.remove(transformer_index);
+            }
+            Change::MoveTransformer(MoveTransformer {
+                state_index,
+                from,
+                to,
+            }) => {
+                let to_be_modified = get_slot(pipeline, state_index)?;
+                check_transformer_index(to_be_modified, from)?;
+                check_transformer_index(to_be_modified, to)?;
+                let transformer = to_be_modified.remove(from);
+                to_be_modified.insert(to, transformer);
+            }
+            Change::ReplaceSampler(sampler) => pipeline.sampler = sampler,
+            Change::ReplaceTerminal(terminal) => pipeline.terminal = terminal,
+            Change::ReplaceNormalizer(normalizer) => pipeline.normalizer = Some(normalizer),
+            Change::RemoveNormalizer => pipeline.normalizer = None,
+            Change::AddStateSlot {
+                state_index,
+                transformers,
+            } => {
+                let state_index = state_index.unwrap_or(pipeline.transformers.len());
+                if state_index > pipeline.transformers.len() {
+                    return Err(Error::msg(format!(
+                        "Can not add a state slot at {state_index}, there are {} slots!",
+                        pipeline.transformers.len()
+                    )));
                 }
+                pipeline.transformers.insert(state_index, transformers);
+            }
+            Change::RemoveStateSlot(RemoveStateSlot { state_index }) => {
+                get_slot(pipeline, state_index)?;
+                if pipeline.transformers.len() == 1 {
+                    return Err(Error::msg(
+                        "Can not remove the only state slot of a pipeline!",
+                    ));
+                }
+                pipeline.transformers.remove(state_index);
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::components::{
+        normalizer::types::Normalizer, sampler::nucleus::NucleusSampler,
+        terminal::lengthed::LengthedTerminal, transformer::logits_compressor::LogitsCompressor,
+        ComponentDump,
+    };
+
+    use super::*;
+
+    /// Normalizes nothing, only to tell whether a pipeline has a normalizer.
+    #[derive(Debug, Clone)]
+    struct Identity;
+
+    impl Normalizer for Identity {
+        fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+            logits
+        }
+
+        fn clone(&self) -> Box<dyn Normalizer> {
+            Box::new(Identity)
+        }
+
+        fn dump(&self) -> Result<ComponentDump> {
+            ComponentDump::new("identity", &())
+        }
+    }
+
+    /// A transformer told apart from others by its factor.
+    fn compressor(factor: f32) -> Box<dyn Transformer> {
+        Box::new(serde_json::from_value::<LogitsCompressor>(json!({"factor": factor})).unwrap())
+    }
+
+    fn pipeline(slots: &[&[f32]]) -> Pipeline {
+        let transformers = slots
+            .iter()
+            .map(|slot| slot.iter().map(|&x| compressor(x)).collect())
+            .collect();
+        let sampler =
+            serde_json::from_value::<NucleusSampler>(json!({"top_p": 1.0, "temp": 1.0})).unwrap();
+        let terminal = serde_json::from_value::<LengthedTerminal>(json!({"length": 16})).unwrap();
+        Pipeline::new(
+            transformers,
+            Box::new(sampler),
+            Box::new(terminal),
+            Some(Box::new(Identity)),
+        )
+    }
+
+    /// Factors of the transformers in each slot, and whether there's a normalizer.
+    fn layout(pipeline: &Pipeline) -> (Vec<Vec<f64>>, bool) {
+        let transformers = pipeline
+            .transformers
+            .iter()
+            .map(|slot| {
+                slot.iter()
+                    .map(|x| {
+                        x.dump().unwrap().params.unwrap()["factor"]
+                            .as_f64()
+                            .unwrap()
+                    })
+                    .collect()
+            })
+            .collect();
+        (transformers, pipeline.normalizer.is_some())
+    }
+
+    fn delete(state_index: usize, transformer_index: usize) -> Change {
+        Change::DeleteTransformer(DeleteTransformer {
+            state_index,
+            transformer_index,
+        })
+    }
+
+    #[test]
+    fn changes_apply_in_order() {
+        let mut pipeline = pipeline(&[&[1.0, 2.0, 3.0], &[4.0]]);
+        let changes = vec![
+            Change::MoveTransformer(MoveTransformer {
+                state_index: 0,
+                from: 0,
+                to: 2,
+            }),
+            delete(0, 0),
+            Change::InsertTransformer {
+                transformer: compressor(5.0),
+                state_index: 1,
+                transformer_index: None,
+            },
+            Change::ReplaceTransformer {
+                transformer: compressor(6.0),
+                state_index: 1,
+                transformer_index: 0,
+            },
+            Change::AddStateSlot {
+                state_index: Some(0),
+                transformers: vec![compressor(7.0)],
+            },
+            Change::RemoveStateSlot(RemoveStateSlot { state_index: 1 }),
+            Change::RemoveNormalizer,
+        ];
+        Change::apply_all(changes, &mut pipeline).unwrap();
+        assert_eq!(layout(&pipeline), (vec![vec![7.0], vec![6.0, 5.0]], false));
+
+        let changes = vec![Change::ReplaceNormalizer(Box::new(Identity))];
+        Change::apply_all(changes, &mut pipeline).unwrap();
+        assert!(pipeline.normalizer.is_some());
+    }
+
+    #[test]
+    fn failed_changes_leave_the_pipeline_unchanged() {
+        let failing = [
+            Change::ReplaceTransformer {
+                transformer: compressor(5.0),
+                state_index: 0,
+                transformer_index: 3,
+            },
+            Change::InsertTransformer {
+                transformer: compressor(5.0),
+                state_index: 3,
+                transformer_index: None,
+            },
+            Change::AddStateSlot {
+                state_index: Some(4),
+                transformers: Vec::new(),
+            },
+            delete(1, 1),
+        ];
+        for failing in failing {
+            let mut pipeline = pipeline(&[&[1.0, 2.0, 3.0], &[4.0]]);
+            let expected = layout(&pipeline);
+            let changes = vec![
+                delete(0, 0),
+                Change::RemoveNormalizer,
+                Change::AddStateSlot {
+                    state_index: None,
+                    transformers: vec![compressor(5.0)],
+                },
+                failing,
+            ];
+            let error = Change::apply_all(changes, &mut pipeline).unwrap_err();
+            assert!(
+                error.to_string().starts_with("Modification 3 failed"),
+                "{error}"
+            );
+            assert_eq!(layout(&pipeline), expected);
+        }
+    }
+}
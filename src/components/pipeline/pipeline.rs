@@ -1,7 +1,12 @@
 use crate::app::AppState;
 
 use crate::components::{
-    infer::updates::{ResetSetting, UpdateSetting},
+    infer::{
+        healing::TokenHealing,
+        logprobs::TokenLogprobs,
+        trace::{top_values, StepTrace, TokenSet, TransformerTrace},
+        updates::{ResetSetting, UpdateSetting},
+    },
     normalizer::types::Normalizer,
     sampler::types::Sampler,
     state::InferTicket,
@@ -20,6 +25,40 @@ pub struct Pipeline {
     pub(super) normalizer: Option<Box<dyn Normalizer>>,
 }
 
+/// Per-request options of `Pipeline::infer`.
+#[derive(Debug, Clone)]
+pub struct InferOptions {
+    pub max_tokens: usize,
+    /// Records log-probabilities with this many top alternatives if set.
+    pub logprobs: Option<usize>,
+    /// Records what every component did in each step.
+    pub trace: bool,
+    /// Restarts the RNG of the sampler from this seed if set.
+    pub seed: Option<u64>,
+    /// Constrains the first tokens to the bytes removed from the prompt if set.
+    pub healing: Option<TokenHealing>,
+}
+
+/// The outcome of a single `Pipeline::infer` call.
+pub struct InferResult {
+    pub last_token: u16,
+    pub inferred_tokens: Vec<u16>,
+    pub end_reason: &'static str,
+    /// Per-token log-probabilities, only recorded when requested.
+    pub logprobs: Option<Vec<TokenLogprobs>>,
+    /// Per-step traces, only recorded when requested.
+    pub trace: Option<Vec<StepTrace>>,
+    /// The seed the sampler started from, if the sampler is random.
+    pub seed: Option<u64>,
+}
+
+/// A sampled token along with the records requested by `InferOptions`.
+pub struct Sampled {
+    pub token: u16,
+    pub logprobs: Option<TokenLogprobs>,
+    pub trace: Option<StepTrace>,
+}
+
 impl Clone for Pipeline {
     fn clone(&self) -> Self {
         Self {
@@ -56,14 +95,35 @@ impl Pipeline {
         reset_setting: ResetSetting,
         update_setting: UpdateSetting,
         tokens: Vec<Vec<u16>>,
-        max_tokens: usize,
+        options: InferOptions,
         state: &AppState,
-    ) -> Result<(u16, Vec<u16>, &'static str)> {
+    ) -> Result<InferResult> {
+        let max_tokens = options.max_tokens;
         self.update_prompt(&tokens, update_setting)?;
+        let seed = self.sampler.reseed(options.seed);
 
-        let logits = ticket.infer(tokens).await;
+        let mut healing = options.healing.as_ref().map(|x| x.start());
+        let mut logits = ticket.infer(tokens).await;
+        if let Some(healing) = healing.as_ref() {
+            healing.mask(&mut logits);
+        }
         let state_count = ticket.state_size();
-        let mut last_token = self.sample(logits, &state).await;
+        let mut records = options.logprobs.map(|_| Vec::with_capacity(max_tokens));
+        let mut traces = options.trace.then(|| Vec::with_capacity(max_tokens));
+        let Sampled {
+            token: mut last_token,
+            logprobs: record,
+            trace,
+        } = self.sample(logits, state, &options).await?;
+        if let (Some(records), Some(record)) = (records.as_mut(), record) {
+            records.push(record);
+        }
+        if let (Some(traces), Some(trace)) = (traces.as_mut(), trace) {
+            traces.push(trace);
+        }
+        if let Some(healing) = healing.as_mut() {
+            healing.advance(state, last_token)?;
+        }
         let mut inferred_tokens = vec![last_token];
 
         let end_reason = loop {
@@ -86,12 +146,36 @@ impl Pipeline {
                 }
                 Err(InferenceInterruption::Error(e)) => Err(e)?,
             }
-            let logits = ticket.infer(token_vec).await;
-            last_token = self.sample(logits, &state).await;
+            let mut logits = ticket.infer(token_vec).await;
+            if let Some(healing) = healing.as_ref().filter(|x| !x.is_done()) {
+                healing.mask(&mut logits);
+            }
+            let Sampled {
+                token,
+                logprobs: record,
+                trace,
+            } = self.sample(logits, state, &options).await?;
+            if let (Some(records), Some(record)) = (records.as_mut(), record) {
+                records.push(record);
+            }
+            if let (Some(traces), Some(trace)) = (traces.as_mut(), trace) {
+                traces.push(trace);
+            }
+            if let Some(healing) = healing.as_mut().filter(|x| !x.is_done()) {
+                healing.advance(state, token)?;
+            }
+            last_token = token;
             inferred_tokens.push(last_token)
         };
 
-        Ok((last_token, inferred_tokens, end_reason))
+        Ok(InferResult {
+            last_token,
+            inferred_tokens,
+            end_reason,
+            logprobs: records,
+            trace: traces,
+            seed,
+        })
     }
 
     pub fn reset_all(&mut self) {
@@ -115,7 +199,7 @@ impl Pipeline {
 }
 
 impl Pipeline {
-    fn update_auto(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+    pub fn update_auto(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
         self.sampler.update(tokens)?;
         if let Some(normalizer) = self.normalizer.as_mut() {
             normalizer.update(tokens)?;
@@ -134,7 +218,7 @@ impl Pipeline {
         Ok(())
     }
 
-    fn update_prompt(
+    pub fn update_prompt(
         &mut self,
         tokens: &Vec<Vec<u16>>,
         update_setting: UpdateSetting,
@@ -171,7 +255,7 @@ impl Pipeline {
         Ok(())
     }
 
-    fn reset(&mut self, reset_setting: ResetSetting) {
+    pub fn reset(&mut self, reset_setting: ResetSetting) {
         let ResetSetting {
             transformers,
             sampler,
@@ -201,7 +285,93 @@ impl Pipeline {
         self.terminal.terminate(result, token_count)
     }
 
-    pub async fn sample(&self, logits: Vec<Vec<f32>>, app_state: &AppState) -> u16 {
+    /// Samples a token from the logits of all states. If `logprobs` is set, the
+    /// log-probability of the sampled token and the top `logprobs` alternatives
+    /// under the final (transformed and normalized) distribution are recorded.
+    /// If `trace` is set, what every component did is recorded.
+    pub async fn sample(
+        &mut self,
+        logits: Vec<Vec<f32>>,
+        app_state: &AppState,
+        options: &InferOptions,
+    ) -> Result<Sampled> {
+        let (probs, transformers) = if options.trace {
+            let (logits, traces) = self.transform_traced(logits);
+            (
+                self.normalize_transformed(logits, app_state).await,
+                Some(traces),
+            )
+        } else {
+            (self.normalize(logits, app_state).await, None)
+        };
+        let first = (options.logprobs.is_some() || options.trace).then(|| probs[0].clone());
+        let candidates = if options.trace {
+            self.sampler.candidates(&probs)
+        } else {
+            None
+        };
+
+        let token = self.sampler.sample(probs);
+        let logprobs = match (options.logprobs, &first) {
+            (Some(top_k), Some(probs)) => Some(TokenLogprobs::new(app_state, probs, token, top_k)?),
+            _ => None,
+        };
+        let trace = transformers
+            .zip(first.as_deref())
+            .map(|(transformers, probs)| StepTrace {
+                transformers,
+                normalized: top_values(probs),
+                candidates: candidates.map(|x| TokenSet::new(x.into_iter())),
+                token,
+            });
+        Ok(Sampled {
+            token,
+            logprobs,
+            trace,
+        })
+    }
+
+    /// Runs the transformers like `normalize` does, recording the logits after
+    /// each of them.
+    fn transform_traced(
+        &self,
+        logits: Vec<Vec<f32>>,
+    ) -> (Vec<Vec<f32>>, Vec<Vec<TransformerTrace>>) {
+        self.transformers
+            .par_iter()
+            .zip(logits.into_par_iter())
+            .map(|(transformers, mut logits)| {
+                let mut masked = vec![false; logits.len()];
+                let mut traces = Vec::with_capacity(transformers.len());
+                for transformer in transformers {
+                    logits = transformer.transform(logits);
+                    traces.push(TransformerTrace::new(&logits, &mut masked));
+                }
+                (logits, traces)
+            })
+            .unzip()
+    }
+
+    /// Samples a token from the logits like `sample`, but also returns the
+    /// distributions fed into the sampler.
+    pub async fn step(
+        &mut self,
+        logits: Vec<Vec<f32>>,
+        app_state: &AppState,
+    ) -> (Vec<Vec<f32>>, u16) {
+        let probs = self.normalize(logits, app_state).await;
+        let token = self.sample_probs(probs.clone());
+        (probs, token)
+    }
+
+    /// Samples a token from distributions which are already normalized.
+    fn sample_probs(&mut self, probs: Vec<Vec<f32>>) -> u16 {
+        self.sampler.sample(probs)
+    }
+
+    /// Runs the transformers and the normalizer (or softmax) on the logits of
+    /// all states, giving the distributions to be fed into the sampler.
+    pub async fn normalize(&self, logits: Vec<Vec<f32>>, app_state: &AppState) -> Vec<Vec<f32>> {
         let logits = self
             .transformers
             .par_iter()
@@ -213,11 +383,60 @@ impl Pipeline {
                 logits
             })
             .collect::<Vec<_>>();
-        let logits = if let Some(normalizer) = &self.normalizer {
+        self.normalize_transformed(logits, app_state).await
+    }
+
+    async fn normalize_transformed(
+        &self,
+        logits: Vec<Vec<f32>>,
+        app_state: &AppState,
+    ) -> Vec<Vec<f32>> {
+        if let Some(normalizer) = &self.normalizer {
             normalizer.normalize(logits)
         } else {
             app_state.softmax(logits).await
-        };
-        self.sampler.sample(logits)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::components::{
+        sampler::nucleus::NucleusSampler, terminal::lengthed::LengthedTerminal,
+    };
+
+    use super::*;
+
+    fn seeded_pipeline() -> Pipeline {
+        let mut sampler: Box<dyn Sampler> = Box::new(
+            serde_json::from_value::<NucleusSampler>(json!({"top_p": 1.0, "temp": 1.0})).unwrap(),
+        );
+        sampler.reseed(Some(42));
+        let terminal = serde_json::from_value::<LengthedTerminal>(json!({"length": 16})).unwrap();
+        Pipeline::new(vec![Vec::new()], sampler, Box::new(terminal), None)
+    }
+
+    fn samples(pipeline: &mut Pipeline) -> Vec<u16> {
+        let probs = vec![vec![1.0 / 256.0; 256]];
+        (0..8)
+            .map(|_| pipeline.sample_probs(probs.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn dry_steps_leave_samples_unchanged() {
+        let expected = samples(&mut seeded_pipeline());
+
+        // A step without `force_token` runs on a copy of the pipeline.
+        let mut pipeline = seeded_pipeline();
+        samples(&mut pipeline.clone());
+        assert_eq!(samples(&mut pipeline), expected);
+
+        // Stepping the pipeline itself advances its sampler.
+        let mut pipeline = seeded_pipeline();
+        samples(&mut pipeline);
+        assert_ne!(samples(&mut pipeline), expected);
     }
 }
@@ -0,0 +1,377 @@
+use anyhow::{Error, Result};
+use ndarray::{s, Array, Array1};
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{
+        sampler::utils::{argsort, sort_by_indices, SamplerRng},
+        ComponentDump,
+    },
+};
+
+use super::types::Sampler;
+
+/// A stage of the chain, applied on the candidates sorted by descending probs.
+///
+/// Filters always keep at least 1 candidate.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Stage {
+    /// Keeps the `k` most probable candidates.
+    TopK { k: usize },
+    /// Keeps candidates with probs of at least `p` times the top prob.
+    MinP { p: f32 },
+    /// Keeps candidates with probs of at least `a` times the square of the top prob.
+    TopA { a: f32 },
+    /// Tail free sampling, cuts off where the second derivative of the probs
+    /// accumulates to `z`.
+    Tfs { z: f32 },
+    /// Keeps candidates with probs above `min(eta, sqrt(eta) * exp(-entropy))`.
+    Eta { eta: f32 },
+    /// Keeps candidates with probs above `epsilon`.
+    Epsilon { epsilon: f32 },
+    /// Keeps the most typical candidates with a total prob of `tau`.
+    Typical { tau: f32 },
+    /// Keeps the most probable candidates with a total prob of `p`.
+    TopP { p: f32 },
+    /// Reshapes the probs by `p ^ (1 / temp)`.
+    Temperature { temp: f32 },
+}
+
+impl Stage {
+    fn validate(&self) -> Result<()> {
+        let (name, value) = match *self {
+            Stage::TopK { .. } => return Ok(()),
+            Stage::Temperature { temp } => {
+                return if temp > 0.0 && temp.is_finite() {
+                    Ok(())
+                } else {
+                    Err(Error::msg("Temperature must be finite and larger than 0!"))
+                };
+            }
+            Stage::MinP { p } | Stage::TopP { p } => ("p", p),
+            Stage::TopA { a } => ("a", a),
+            Stage::Tfs { z } => ("z", z),
+            Stage::Eta { eta } => ("eta", eta),
+            Stage::Epsilon { epsilon } => ("epsilon", epsilon),
+            Stage::Typical { tau } => ("tau", tau),
+        };
+        if value >= 0.0 && value.is_finite() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "Stage param {name} must be finite and not less than 0!"
+            )))
+        }
+    }
+}
+
+/// How the token is picked from the candidates left by the stages.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Draw {
+    #[default]
+    Weighted,
+    Greedy,
+}
+
+/// Candidate tokens sorted by descending probs, the probs always sum to 1.
+struct Candidates {
+    ids: Array1<usize>,
+    probs: Array1<f32>,
+}
+
+impl Candidates {
+    fn new(probs: Vec<f32>) -> Self {
+        let mut probs = Array::from_vec(probs);
+        let reversed_probs = -probs.clone();
+        let ids = argsort(reversed_probs.view());
+        sort_by_indices(probs.view_mut(), ids.view());
+        let mut candidates = Self { ids, probs };
+        candidates.normalize();
+        candidates
+    }
+
+    fn normalize(&mut self) {
+        let sum = self.probs.sum();
+        if sum > 0.0 {
+            self.probs /= sum;
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        let len = len.clamp(1, self.probs.len());
+        self.ids = self.ids.slice(s![..len]).to_owned();
+        self.probs = self.probs.slice(s![..len]).to_owned();
+        self.normalize();
+    }
+
+    /// Keeps the leading candidates while `keep` holds, which must be monotone.
+    fn keep_while(&mut self, keep: impl Fn(f32) -> bool) {
+        let len = self
+            .probs
+            .iter()
+            .position(|&x| !keep(x))
+            .unwrap_or(self.probs.len());
+        self.truncate(len);
+    }
+
+    /// Keeps the leading candidates until their probs accumulate to `mass`.
+    fn keep_mass(&mut self, mass: f32) {
+        let mut sum = 0.0;
+        let len = self
+            .probs
+            .iter()
+            .position(|x| {
+                sum += x;
+                sum >= mass
+            })
+            .map(|x| x + 1)
+            .unwrap_or(self.probs.len());
+        self.truncate(len);
+    }
+
+    fn entropy(&self) -> f32 {
+        -self
+            .probs
+            .iter()
+            .filter(|&&x| x > 0.0)
+            .map(|x| x * x.ln())
+            .sum::<f32>()
+    }
+
+    fn apply(&mut self, stage: Stage) {
+        match stage {
+            Stage::TopK { k } => self.truncate(k),
+            Stage::MinP { p } => {
+                let threshold = p * self.probs[0];
+                self.keep_while(|x| x >= threshold)
+            }
+            Stage::TopA { a } => {
+                let threshold = a * self.probs[0] * self.probs[0];
+                self.keep_while(|x| x >= threshold)
+            }
+            Stage::Tfs { z } => {
+                if self.probs.len() < 3 {
+                    return;
+                }
+                let second_derivatives = self
+                    .probs
+                    .windows(3)
+                    .into_iter()
+                    .map(|x| ((x[0] - x[1]) - (x[1] - x[2])).abs())
+                    .collect::<Vec<_>>();
+                let total = second_derivatives.iter().sum::<f32>();
+                if total <= 0.0 {
+                    return;
+                }
+                let mut sum = 0.0;
+                let len = second_derivatives
+                    .iter()
+                    .position(|x| {
+                        sum += x / total;
+                        sum > z
+                    })
+                    .unwrap_or(self.probs.len());
+                self.truncate(len)
+            }
+            Stage::Eta { eta } => {
+                let threshold = eta.min(eta.sqrt() * (-self.entropy()).exp());
+                self.keep_while(|x| x > threshold)
+            }
+            Stage::Epsilon { epsilon } => self.keep_while(|x| x > epsilon),
+            Stage::Typical { tau } => {
+                let entropy = self.entropy();
+                let scores = self.probs.mapv(|x| {
+                    let score = (-x.ln() - entropy).abs();
+                    if score.is_nan() {
+                        f32::MAX
+                    } else {
+                        score
+                    }
+                });
+                let order = argsort(scores.view());
+                let mut sum = 0.0;
+                let len = order
+                    .iter()
+                    .position(|&x| {
+                        sum += self.probs[x];
+                        sum >= tau
+                    })
+                    .map(|x| x + 1)
+                    .unwrap_or(order.len());
+                // Typical candidates are kept in the order of probs.
+                let mut kept = order.slice(s![..len]).to_vec();
+                kept.sort_unstable();
+                self.ids = kept.iter().map(|&x| self.ids[x]).collect();
+                self.probs = kept.iter().map(|&x| self.probs[x]).collect();
+                self.normalize();
+            }
+            Stage::TopP { p } => self.keep_mass(p),
+            Stage::Temperature { temp } => {
+                if temp != 1.0 {
+                    self.probs.par_mapv_inplace(|x| x.powf(1.0 / temp));
+                    self.normalize();
+                }
+            }
+        }
+    }
+}
+
+/// Applies a chain of stages on the probs, then picks a token from what's left.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainSampler {
+    stages: Vec<Stage>,
+    #[serde(default)]
+    draw: Draw,
+    seed: Option<u64>,
+    #[serde(skip)]
+    rng: SamplerRng,
+}
+
+impl ChainSampler {
+    pub fn initialize(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+        let mut data = serde_json::from_value::<ChainSampler>(data.ok_or(Error::msg(
+            "Invalid chain sampler data. Example format:{
+                stages: [{ type: \"top_k\", k: 40 }, { type: \"temperature\", temp: 0.8 }],
+                draw: Option<\"weighted\" | \"greedy\">,
+                seed: Option<u64>,
+            }",
+        ))?)?;
+        for stage in data.stages.iter() {
+            stage.validate()?;
+        }
+        data.rng = SamplerRng::new(data.seed);
+        Ok(Box::new(data))
+    }
+
+    fn filter(&self, probs: Vec<f32>) -> Candidates {
+        let mut candidates = Candidates::new(probs);
+        for stage in self.stages.iter() {
+            candidates.apply(*stage);
+        }
+        candidates
+    }
+}
+
+impl Sampler for ChainSampler {
+    fn sample(&mut self, probs: Vec<Vec<f32>>) -> u16 {
+        let Candidates { ids, probs } = self.filter(probs[0].clone());
+        let index = match self.draw {
+            Draw::Greedy => 0,
+            Draw::Weighted => match WeightedIndex::new(probs.as_slice().unwrap()) {
+                Ok(index) => index.sample(self.rng.rng()),
+                Err(_) => 0,
+            },
+        };
+        ids[index] as u16
+    }
+
+    fn candidates(&self, probs: &[Vec<f32>]) -> Option<Vec<u16>> {
+        Some(
+            self.filter(probs[0].clone())
+                .ids
+                .iter()
+                .map(|&x| x as u16)
+                .collect(),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.rng.clear();
+    }
+
+    fn reseed(&mut self, seed: Option<u64>) -> Option<u64> {
+        match self.draw {
+            Draw::Weighted => Some(self.rng.reseed(seed)),
+            Draw::Greedy => None,
+        }
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(ChainSampler {
+            stages: self.stages.clone(),
+            draw: self.draw,
+            seed: self.seed,
+            rng: self.rng.clone(),
+        })
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("chain", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn candidates(probs: &[f32], stage: Stage) -> Vec<usize> {
+        let mut candidates = Candidates::new(probs.to_vec());
+        candidates.apply(stage);
+        candidates.ids.to_vec()
+    }
+
+    #[test]
+    fn filters_keep_leading_candidates() {
+        let probs = [0.1, 0.5, 0.05, 0.3, 0.05];
+        assert_eq!(candidates(&probs, Stage::TopK { k: 2 }), vec![1, 3]);
+        assert_eq!(candidates(&probs, Stage::MinP { p: 0.2 }), vec![1, 3, 0]);
+        assert_eq!(candidates(&probs, Stage::TopP { p: 0.8 }), vec![1, 3]);
+        assert_eq!(
+            candidates(&probs, Stage::Epsilon { epsilon: 0.2 }),
+            vec![1, 3]
+        );
+        // Filters never leave nothing.
+        assert_eq!(candidates(&probs, Stage::MinP { p: 2.0 }), vec![1]);
+        assert_eq!(candidates(&probs, Stage::TopK { k: 0 }), vec![1]);
+    }
+
+    #[test]
+    fn probs_are_renormalized() {
+        let mut candidates = Candidates::new(vec![0.1, 0.5, 0.05, 0.3, 0.05]);
+        candidates.apply(Stage::TopK { k: 2 });
+        assert!((candidates.probs.sum() - 1.0).abs() < 1e-6);
+        candidates.apply(Stage::Temperature { temp: 0.5 });
+        assert!((candidates.probs.sum() - 1.0).abs() < 1e-6);
+        assert!(candidates.probs[0] > 0.5 / 0.8);
+    }
+
+    #[test]
+    fn invalid_stages_are_rejected() {
+        for stage in [
+            Stage::MinP { p: -0.1 },
+            Stage::TopP { p: f32::NAN },
+            Stage::TopA { a: -1.0 },
+            Stage::Tfs { z: f32::NAN },
+            Stage::Eta { eta: f32::INFINITY },
+            Stage::Epsilon { epsilon: -1e-3 },
+            Stage::Typical { tau: f32::NAN },
+            Stage::Temperature { temp: 0.0 },
+            Stage::Temperature { temp: f32::NAN },
+        ] {
+            assert!(stage.validate().is_err(), "{stage:?}");
+        }
+        assert!(Stage::TopP { p: 0.9 }.validate().is_ok());
+    }
+
+    #[test]
+    fn unseeded_copies_sample_apart() {
+        let mut sampler: ChainSampler = serde_json::from_value(json!({ "stages": [] })).unwrap();
+        sampler.rng = SamplerRng::new(None);
+        let probs = vec![1.0 / 256.0; 256];
+        let tokens = (0..8)
+            .map(|_| {
+                let mut copy = Sampler::clone(&sampler);
+                copy.reseed(None);
+                copy.sample(vec![probs.clone()])
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(tokens.len() > 1);
+    }
+}
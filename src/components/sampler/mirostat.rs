@@ -0,0 +1,230 @@
+use anyhow::{Error, Result};
+use ndarray::{s, Array, Array1};
+use rand::distributions::{Distribution, WeightedIndex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{
+        sampler::utils::{argsort, sort_by_indices, SamplerRng},
+        ComponentDump, InferenceInterruption,
+    },
+};
+
+use super::types::Sampler;
+
+/// Mirostat sampler, which adapts its cutoff to keep the surprise of sampled
+/// tokens around `tau`.
+///
+/// The running `mu` is adjusted when the sampled token is fed back by `update`,
+/// so copies of the sampler continue with the same adaptation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MirostatSampler {
+    tau: f32,
+    eta: f32,
+    #[serde(default = "MirostatSampler::default_version")]
+    version: u8,
+    /// Count of tokens used to estimate the Zipf exponent in v1.
+    #[serde(default = "MirostatSampler::default_m")]
+    m: usize,
+    seed: Option<u64>,
+    #[serde(skip)]
+    mu: f32,
+    /// The last sampled token along with its surprise, waiting to be fed back.
+    #[serde(skip)]
+    sampled: Option<(u16, f32)>,
+    #[serde(skip)]
+    rng: SamplerRng,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MirostatState {
+    mu: f32,
+}
+
+impl MirostatSampler {
+    fn default_version() -> u8 {
+        2
+    }
+
+    fn default_m() -> usize {
+        100
+    }
+
+    pub fn initialize(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+        let mut data = serde_json::from_value::<MirostatSampler>(data.ok_or(Error::msg(
+            "Invalid mirostat sampler data. Example format:{
+                tau: f32,
+                eta: f32,
+                version: Option<u8>,
+                m: Option<usize>,
+                seed: Option<u64>,
+            }",
+        ))?)?;
+        if data.version != 1 && data.version != 2 {
+            return Err(Error::msg("data.version must be either 1 or 2!"));
+        }
+        if !(data.tau > 0.0 && data.tau.is_finite() && data.eta > 0.0 && data.eta.is_finite()) {
+            return Err(Error::msg(
+                "data.tau and data.eta must be finite and larger than 0!",
+            ));
+        }
+        if data.m == 0 {
+            return Err(Error::msg("data.m must be larger than 0!"));
+        }
+        data.mu = 2.0 * data.tau;
+        data.rng = SamplerRng::new(data.seed);
+        Ok(Box::new(data))
+    }
+
+    /// Sorts the probs in descending order and cuts off the tail by `mu`,
+    /// returning the sorted token ids along with the probs kept.
+    fn cut_off(&self, probs: Vec<f32>) -> (Array1<usize>, Array1<f32>) {
+        let mut probs = Array::from_vec(probs);
+        let reversed_probs = -probs.clone();
+        let sorted_ids = argsort(reversed_probs.view());
+        sort_by_indices(probs.view_mut(), sorted_ids.view());
+        let kept = match self.version {
+            1 => self.estimate_top_k(probs.as_slice().unwrap()),
+            _ => probs
+                .iter()
+                .position(|x| -x.log2() > self.mu)
+                .unwrap_or(probs.len())
+                .max(1),
+        };
+        (sorted_ids, probs.slice_move(s![..kept]))
+    }
+
+    /// Estimates the top k for v1 from the Zipf exponent of the sorted probs.
+    fn estimate_top_k(&self, sorted: &[f32]) -> usize {
+        let mut numerator = 0.0;
+        let mut denominator = 0.0;
+        for i in 0..self.m.min(sorted.len() - 1) {
+            let t = ((i + 2) as f32 / (i + 1) as f32).ln();
+            let b = (sorted[i] / sorted[i + 1]).ln();
+            if b.is_finite() {
+                numerator += t * b;
+                denominator += t * t;
+            }
+        }
+        let s_hat = if denominator > 0.0 {
+            numerator / denominator
+        } else {
+            1.0
+        };
+        let epsilon = s_hat - 1.0;
+        let n = sorted.len() as f32;
+        let k = (epsilon * 2f32.powf(self.mu) / (1.0 - n.powf(-epsilon))).powf(1.0 / s_hat);
+        if k.is_finite() {
+            (k.round() as usize).clamp(1, sorted.len())
+        } else {
+            sorted.len()
+        }
+    }
+}
+
+impl Sampler for MirostatSampler {
+    fn update(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        if let Some((token, surprise)) = self.sampled.take() {
+            if tokens.first().and_then(|x| x.first()) == Some(&token) {
+                self.mu -= self.eta * (surprise - self.tau);
+            }
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self, probs: Vec<Vec<f32>>) -> u16 {
+        let (sorted_ids, probs) = self.cut_off(probs[0].clone());
+        let index = match WeightedIndex::new(probs.as_slice().unwrap()) {
+            Ok(index) => index.sample(self.rng.rng()),
+            Err(_) => 0,
+        };
+        let token = sorted_ids[index] as u16;
+        self.sampled = Some((token, -(probs[index] / probs.sum()).log2()));
+        token
+    }
+
+    fn candidates(&self, probs: &[Vec<f32>]) -> Option<Vec<u16>> {
+        let (sorted_ids, probs) = self.cut_off(probs[0].clone());
+        Some(
+            sorted_ids
+                .iter()
+                .take(probs.len())
+                .map(|&x| x as u16)
+                .collect(),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.mu = 2.0 * self.tau;
+        self.sampled = None;
+        self.rng.clear();
+    }
+
+    fn reseed(&mut self, seed: Option<u64>) -> Option<u64> {
+        Some(self.rng.reseed(seed))
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(MirostatSampler {
+            tau: self.tau,
+            eta: self.eta,
+            version: self.version,
+            m: self.m,
+            seed: self.seed,
+            mu: self.mu,
+            sampled: self.sampled,
+            rng: self.rng.clone(),
+        })
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("mirostat", self)?.with_state(&MirostatState { mu: self.mu })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let MirostatState { mu } = serde_json::from_value(state)?;
+        self.mu = mu;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn sampler(seed: Option<u64>) -> MirostatSampler {
+        let mut sampler: MirostatSampler =
+            serde_json::from_value(json!({ "tau": 8.0, "eta": 0.1, "seed": seed })).unwrap();
+        sampler.mu = 2.0 * sampler.tau;
+        sampler.rng = SamplerRng::new(seed);
+        sampler
+    }
+
+    /// Samples from discarded copies, like a forked infer does.
+    fn sample_copies(sampler: &MirostatSampler) -> HashSet<u16> {
+        let probs = vec![1.0 / 256.0; 256];
+        (0..8)
+            .map(|_| {
+                let mut copy = Sampler::clone(sampler);
+                copy.reseed(None);
+                copy.sample(vec![probs.clone()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unseeded_copies_sample_apart() {
+        assert!(sample_copies(&sampler(None)).len() > 1);
+    }
+
+    #[test]
+    fn seeded_copies_replay() {
+        assert_eq!(sample_copies(&sampler(Some(42))).len(), 1);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod chain;
+pub mod mirostat;
 pub mod nucleus;
 pub mod types;
 pub mod typical;
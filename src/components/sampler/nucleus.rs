@@ -1,24 +1,32 @@
 use super::types::Sampler;
 use crate::{
     app::AppState,
-    components::sampler::utils::{argsort, sort_by_indices},
+    components::{
+        sampler::utils::{argsort, sort_by_indices, SamplerRng},
+        ComponentDump,
+    },
 };
 use anyhow::{Error, Result};
-use ndarray::{s, Array};
+use ndarray::{s, Array, Array1};
 use rand::{distributions::WeightedIndex, prelude::Distribution};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 /// Test sampler for logits
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NucleusSampler {
     top_p: f32,
     temp: f32,
+    seed: Option<u64>,
+    #[serde(skip)]
+    rng: SamplerRng,
 }
 
-impl Sampler for NucleusSampler {
-    fn sample(&self, mut probs: Vec<Vec<f32>>) -> u16 {
-        let mut probs = Array::from_vec(probs.remove(0));
+impl NucleusSampler {
+    /// Sorts the probs in descending order and cuts off the tail out of `top_p`,
+    /// returning the sorted token ids along with the probs kept.
+    fn cut_off(&self, probs: Vec<f32>) -> (Array1<usize>, Array1<f32>) {
+        let mut probs = Array::from_vec(probs);
         let reversed_probs = -probs.clone();
         let sorted_ids = argsort(reversed_probs.view());
         sort_by_indices(probs.view_mut(), sorted_ids.view());
@@ -30,36 +38,68 @@ impl Sampler for NucleusSampler {
                 temp >= self.top_p
             })
             .unwrap_or(probs.len() - 1);
-        let mut probs = probs.slice_move(s![..cut_off + 1]);
+        (sorted_ids, probs.slice_move(s![..cut_off + 1]))
+    }
+}
+
+impl Sampler for NucleusSampler {
+    fn sample(&mut self, mut probs: Vec<Vec<f32>>) -> u16 {
+        let (sorted_ids, mut probs) = self.cut_off(probs.remove(0));
         if self.temp != 1.0 {
             probs.par_mapv_inplace(|x| x.powf(1.0 / self.temp));
         }
-        let mut rng = rand::thread_rng();
         let token_id = sorted_ids[WeightedIndex::new(probs.as_slice().unwrap())
             .unwrap()
-            .sample(&mut rng)] as u16;
+            .sample(self.rng.rng())] as u16;
         token_id
     }
 
+    fn candidates(&self, probs: &[Vec<f32>]) -> Option<Vec<u16>> {
+        let (sorted_ids, probs) = self.cut_off(probs[0].clone());
+        Some(
+            sorted_ids
+                .iter()
+                .take(probs.len())
+                .map(|&x| x as u16)
+                .collect(),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.rng.clear();
+    }
+
+    fn reseed(&mut self, seed: Option<u64>) -> Option<u64> {
+        Some(self.rng.reseed(seed))
+    }
+
     fn clone(&self) -> Box<dyn Sampler> {
         Box::new(Self {
             top_p: self.top_p,
             temp: self.temp,
+            seed: self.seed,
+            rng: self.rng.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("nucleus", self)
+    }
 }
 
 pub fn initialize(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
-    let data = serde_json::from_value::<NucleusSampler>(data.ok_or(Error::msg(
+    let mut data = serde_json::from_value::<NucleusSampler>(data.ok_or(Error::msg(
         "
         Invalid NucleusSampler data. Example format:{
             top_p: f32,
-            temp: f32
+            temp: f32,
+            seed: Option<u64>
         }
         ",
     ))?)?;
     if data.temp == 0.0 {
         return Err(Error::msg("data.temp must be larger than 0!"));
     }
+    data.rng = SamplerRng::new(data.seed);
     Ok(Box::new(data))
 }
@@ -1,8 +1,9 @@
 use std::fmt::Debug;
 
 use anyhow::{Error, Result};
+use serde_json::Value;
 
-use crate::components::InferenceInterruption;
+use crate::components::{ComponentDump, InferenceInterruption};
 
 /// Sample a token from probablities (after softmax).
 ///
@@ -39,10 +40,28 @@ pub trait Sampler: Send + Sync + Debug {
     /// like `typical` or `nucleus` would do, but there are also sampling methods like
     /// `CFG Sampling` which samples from multiple parallel states. Note that only 1
     /// token will be sampled from the list and selected as the next token for *all states*.
-    fn sample(&self, probs: Vec<Vec<f32>>) -> u16;
+    fn sample(&mut self, probs: Vec<Vec<f32>>) -> u16;
+    /// Returns the tokens which the sampler would sample from, after its cutoff (e.g. `top_p`).
+    ///
+    /// This is only used to trace a generation, so it's fine to be slow. By default the sampler
+    /// has no cutoff, and `None` is returned.
+    #[allow(unused_variables)]
+    fn candidates(&self, probs: &[Vec<f32>]) -> Option<Vec<u16>> {
+        None
+    }
     /// Clears the `Sampler`. This will reset the internal state of the sampler to *when it
     /// is just constructed from params*.
     fn clear(&mut self) {}
+    /// Restarts the RNG of the `Sampler` from a seed, or a new seed if `None`, so that a
+    /// generation can be replayed with the returned seed. The new seed must not depend on
+    /// the RNG only unless the sampler is seeded, since copies of the sampler may be
+    /// discarded and would otherwise repeat a same generation.
+    ///
+    /// By default the sampler is deterministic, and `None` is returned.
+    #[allow(unused_variables)]
+    fn reseed(&mut self, seed: Option<u64>) -> Option<u64> {
+        None
+    }
     /// Copies the internal state (no matter if it's from construction or temporal calculation),
     /// and construct a new `Sampler` from the state.
     ///
@@ -50,6 +69,19 @@ pub trait Sampler: Send + Sync + Debug {
     /// that the state mutated in `update` will not mutate the cloned state, it is safe to
     /// share internal state by using `Arc`, etc.
     fn clone(&self) -> Box<dyn Sampler>;
+    /// Dumps the construction params along with the internal state of the `Sampler`,
+    /// so it can be persisted and restored later.
+    ///
+    /// The `type_id` of the dump must be the one the `Sampler` is registered with.
+    fn dump(&self) -> Result<ComponentDump>;
+    /// Restores the internal state from a dump, after the `Sampler` is constructed
+    /// from the dumped params.
+    ///
+    /// By default the `Sampler` has no internal state, so nothing is restored.
+    #[allow(unused_variables)]
+    fn restore(&mut self, state: Value) -> Result<()> {
+        Ok(())
+    }
     /// Implements a special update for prompt, where it will not throw InferenceInterruption::Exhaustion
     /// if the exhaustion happened. This is for special components like BNF which might exhaust when
     /// reading prompt.
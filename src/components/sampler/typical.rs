@@ -1,37 +1,47 @@
 use anyhow::{Error, Result};
-use ndarray::{self, s, Array};
+use ndarray::{self, s, Array, Array1};
 use rand::distributions::{Distribution, WeightedIndex};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{app::AppState, components::sampler::utils::argsort};
+use crate::{
+    app::AppState,
+    components::{
+        sampler::utils::{argsort, SamplerRng},
+        ComponentDump,
+    },
+};
 
 use super::{types::Sampler, utils};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TypicalSampler {
     tau: f32,
     temp: f32,
+    seed: Option<u64>,
+    #[serde(skip)]
+    rng: SamplerRng,
 }
 
 impl TypicalSampler {
     pub fn initialize(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
-        let data = serde_json::from_value::<TypicalSampler>(data.ok_or(Error::msg(
+        let mut data = serde_json::from_value::<TypicalSampler>(data.ok_or(Error::msg(
             "Invalid typical sampler data. Example format:{
                 tau: f32,
                 temp: f32,
+                seed: Option<u64>,
             }",
         ))?)?;
         if data.temp == 0.0 {
             return Err(Error::msg("data.temp must be larger than 0!"));
         }
+        data.rng = SamplerRng::new(data.seed);
         Ok(Box::new(data))
     }
-}
 
-impl Sampler for TypicalSampler {
-    fn sample(&self, probs: Vec<Vec<f32>>) -> u16 {
-        let probs = probs[0].clone();
+    /// Sorts the probs by how typical they are and cuts off the tail out of `tau`,
+    /// returning the sorted token ids along with the probs kept.
+    fn cut_off(&self, probs: Vec<f32>) -> (Array1<usize>, Array1<f32>) {
         let mut probs = Array::from_vec(probs);
         let mut logits = probs.clone();
         logits.par_mapv_inplace(|x| -x.ln());
@@ -49,26 +59,56 @@ impl Sampler for TypicalSampler {
                 temp >= self.tau
             })
             .unwrap_or(probs.len() - 1);
-        let mut probs = probs.slice_move(s![..cut_off + 1]);
+        (sorted_ids, probs.slice_move(s![..cut_off + 1]))
+    }
+}
+
+impl Sampler for TypicalSampler {
+    fn sample(&mut self, probs: Vec<Vec<f32>>) -> u16 {
+        let (sorted_ids, mut probs) = self.cut_off(probs[0].clone());
         if self.temp != 1.0 {
             probs.par_mapv_inplace(|x| x.powf(1.0 / self.temp));
         }
-        let mut rng = rand::thread_rng();
         let token_id = if probs.len() == 1 {
             sorted_ids[0] as u16
         } else {
             sorted_ids[match WeightedIndex::new(probs.as_slice().unwrap()) {
-                Ok(index) => index.sample(&mut rng),
+                Ok(index) => index.sample(self.rng.rng()),
                 Err(_) => 0,
             }] as u16
         };
         token_id
     }
 
+    fn candidates(&self, probs: &[Vec<f32>]) -> Option<Vec<u16>> {
+        let (sorted_ids, probs) = self.cut_off(probs[0].clone());
+        Some(
+            sorted_ids
+                .iter()
+                .take(probs.len())
+                .map(|&x| x as u16)
+                .collect(),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.rng.clear();
+    }
+
+    fn reseed(&mut self, seed: Option<u64>) -> Option<u64> {
+        Some(self.rng.reseed(seed))
+    }
+
     fn clone(&self) -> Box<dyn Sampler> {
         Box::new(TypicalSampler {
             tau: self.tau,
             temp: self.temp,
+            seed: self.seed,
+            rng: self.rng.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("typical", self)
+    }
 }
@@ -1,8 +1,58 @@
 use bit_set::BitSet;
 use ndarray::{Array, Array1, ArrayView1, ArrayViewMut1};
 use ordered_float::NotNan;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 
+/// A seedable RNG of a sampler. A seeded RNG restarts from its seed when
+/// cleared, while an unseeded one keeps drawing fresh values.
+#[derive(Debug, Clone)]
+pub struct SamplerRng {
+    /// The seed the sampler is created with, if any.
+    seed: Option<u64>,
+    rng: StdRng,
+}
+
+impl SamplerRng {
+    /// Creates the RNG from a seed, or a random one if omitted.
+    pub fn new(seed: Option<u64>) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed.unwrap_or_else(rand::random)),
+        }
+    }
+
+    /// Restarts the RNG from a seed. If omitted, a seeded RNG draws the seed
+    /// from itself, so it gives the same seeds in order, while an unseeded one
+    /// uses a random seed, since a copy of the sampler may be discarded without
+    /// moving the stored RNG. Returns the seed used.
+    pub fn reseed(&mut self, seed: Option<u64>) -> u64 {
+        let seed = seed.unwrap_or_else(|| match self.seed {
+            Some(_) => self.rng.gen(),
+            None => rand::random(),
+        });
+        self.rng = StdRng::seed_from_u64(seed);
+        seed
+    }
+
+    /// Restarts the RNG from the seed it's created with, if any.
+    pub fn clear(&mut self) {
+        if let Some(seed) = self.seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+}
+
+impl Default for SamplerRng {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
 pub fn argsort(data: ArrayView1<f32>) -> Array1<usize> {
     let mut indices = (0..data.len()).collect::<Vec<_>>();
     indices.par_sort_unstable_by_key(|x| NotNan::new(data[*x]).unwrap());
@@ -26,3 +76,38 @@ pub fn sort_by_indices(mut data: ArrayViewMut1<f32>, indices: ArrayView1<usize>)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::SamplerRng;
+
+    #[test]
+    fn unseeded_copies_draw_fresh_seeds() {
+        let rng = SamplerRng::new(None);
+        let seeds = (0..4)
+            .map(|_| rng.clone().reseed(None))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(seeds.len(), 4);
+    }
+
+    #[test]
+    fn seeded_copies_draw_same_seeds() {
+        let rng = SamplerRng::new(Some(42));
+        assert_eq!(rng.clone().reseed(None), rng.clone().reseed(None));
+    }
+
+    #[test]
+    fn clear_restarts_seeded_only() {
+        let mut rng = SamplerRng::new(Some(42));
+        let first: u64 = rng.rng().gen();
+        rng.clear();
+        assert_eq!(first, rng.rng().gen::<u64>());
+
+        let mut rng = SamplerRng::new(None);
+        let first: u64 = rng.rng().gen();
+        rng.clear();
+        assert_ne!(first, rng.rng().gen::<u64>());
+    }
+}
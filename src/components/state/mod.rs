@@ -1,4 +1,11 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{Error, Result};
 use futures_util::{
@@ -13,10 +20,9 @@ use web_rwkv::context::Context;
 
 use crate::config::ModelConfig;
 
-use self::{
-    pool::{InferPool, InferRequest},
-    state::NamedState,
-};
+use self::pool::{InferPool, InferRequest};
+
+pub use self::state::NamedState;
 
 use super::model::AxumModel;
 
@@ -24,14 +30,21 @@ mod pool;
 mod serde;
 mod state;
 
+/// Prefix of ids of anonymous forked states. Forks are never put into the
+/// state map, the prefix only keeps them apart from client states in the
+/// infer pool.
+const FORK_PREFIX: &str = "\u{0}fork:";
+
 struct InnerStates {
     context: Context,
     model: Arc<AxumModel>,
     pool: InferPool,
     states: RwLock<HashMap<String, NamedState>>,
+    readonly: RwLock<HashSet<String>>,
     request_queue: mpsc::Sender<Vec<InferRequest>>,
     state_size: Option<usize>,
     task_lock: Arc<Semaphore>,
+    fork_counter: AtomicUsize,
 }
 
 pub struct InferTicket {
@@ -39,7 +52,7 @@ pub struct InferTicket {
     logits_receivers: Vec<mpsc::Receiver<Vec<f32>>>,
     // When this is dropped, the semaphore is released
     // so no need to r/w anything here
-    _permit: OwnedSemaphorePermit,
+    _permit: Arc<OwnedSemaphorePermit>,
 }
 
 impl InferTicket {
@@ -61,7 +74,7 @@ impl InferTicket {
             InferTicket {
                 token_senders: sender_vec,
                 logits_receivers: receiver_vec,
-                _permit: permit,
+                _permit: Arc::new(permit),
             },
             requests_vec,
         )
@@ -82,6 +95,26 @@ impl InferTicket {
     pub fn state_size(&self) -> usize {
         self.token_senders.len()
     }
+
+    /// Splits the ticket into tickets of `size` states each, in order. Each of
+    /// them can be inferred or dropped independently, while the permit is only
+    /// released after all of them are dropped.
+    pub fn split(self, size: usize) -> Vec<InferTicket> {
+        let mut senders = self.token_senders.into_iter();
+        let mut receivers = self.logits_receivers.into_iter();
+        let mut tickets = Vec::with_capacity(senders.len() / size.max(1));
+        loop {
+            let token_senders = senders.by_ref().take(size).collect::<Vec<_>>();
+            if token_senders.is_empty() {
+                break tickets;
+            }
+            tickets.push(InferTicket {
+                logits_receivers: receivers.by_ref().take(token_senders.len()).collect(),
+                token_senders,
+                _permit: self._permit.clone(),
+            });
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -101,13 +134,16 @@ impl InferStates {
             model,
             pool,
             states: RwLock::new(HashMap::with_capacity(128)),
+            readonly: RwLock::new(HashSet::new()),
             request_queue: sender,
             state_size: config.model.get_max_state_size(),
             task_lock: Arc::new(Semaphore::new(config.model.get_max_concurrency())),
+            fork_counter: AtomicUsize::new(0),
         })))
     }
 
     pub async fn create_ticket(&self, states: Vec<String>) -> Result<InferTicket> {
+        self.ensure_writable(&states).await?;
         let states = stream::iter(states.into_iter())
             .then(|x| async move {
                 self.get_state(&x)
@@ -117,7 +153,12 @@ impl InferStates {
             .collect::<Vec<_>>()
             .await;
         let states = states.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(self.create_ticket_from(states).await)
+    }
 
+    /// Creates a ticket from states directly, which don't have to be registered
+    /// in the state map (e.g. forks).
+    pub async fn create_ticket_from(&self, states: Vec<NamedState>) -> InferTicket {
         let permit = self
             .0
             .task_lock
@@ -128,7 +169,7 @@ impl InferStates {
 
         let (ticket, request) = InferTicket::create_ticket(states, permit);
         self.0.request_queue.send(request).await.unwrap();
-        Ok(ticket)
+        ticket
     }
 
     pub async fn create_state(&self, state_id: &str) -> Result<()> {
@@ -177,6 +218,68 @@ impl InferStates {
         Ok(())
     }
 
+    /// Creates `count` anonymous copies of a state. Forks are not registered,
+    /// so they're discarded once dropped and the source state is never mutated.
+    pub async fn fork_state(&self, src: &str, count: usize) -> Result<Vec<NamedState>> {
+        let src_state = self
+            .get_state(src)
+            .await
+            .ok_or(Error::msg("Source state id doesn't exist!"))?;
+        let mut forks = Vec::with_capacity(count);
+        for _ in 0..count {
+            forks.push(self.fork_named(&src_state).await?);
+        }
+        Ok(forks)
+    }
+
+    /// Creates an anonymous copy of a state which may not be registered.
+    pub async fn fork_named(&self, state: &NamedState) -> Result<NamedState> {
+        self.0.pool.sync(state.get_id()).await;
+        state.clone_new(self.fork_id()).await
+    }
+
+    /// Writes the content of a state (usually a fork) back into a registered
+    /// state. Its slot in the infer pool is dropped, so the stale content there
+    /// is neither reused nor written back.
+    pub async fn overwrite_state(&self, dst: &str, src: &NamedState) -> Result<()> {
+        self.ensure_writable(&[dst.to_string()]).await?;
+        let dst_state = self
+            .get_state(dst)
+            .await
+            .ok_or(Error::msg("Destination state id doesn't exist!"))?;
+        self.0.pool.sync(src.get_id()).await;
+        self.0.pool.discard(dst).await;
+        dst_state.copy_from(src).await;
+        Ok(())
+    }
+
+    /// Creates `count` anonymous empty states, which are discarded once dropped.
+    pub fn scratch_states(&self, count: usize) -> Vec<NamedState> {
+        (0..count)
+            .map(|_| {
+                NamedState::new(
+                    self.fork_id(),
+                    self.0.context.clone(),
+                    self.0.model.clone(),
+                    self.0.state_size,
+                )
+            })
+            .collect()
+    }
+
+    /// Writes the content of an anonymous state back from the infer pool, in
+    /// case it is still held in a slot.
+    pub async fn sync_fork(&self, state: &NamedState) {
+        self.0.pool.sync(state.get_id()).await;
+    }
+
+    fn fork_id(&self) -> String {
+        format!(
+            "{FORK_PREFIX}{}",
+            self.0.fork_counter.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
     pub async fn dump_state(&self, src: &str, path: PathBuf) -> Result<()> {
         if !self.has_state(src).await {
             return Err(Error::msg("Source state id doesn't exist!"));
@@ -192,12 +295,28 @@ impl InferStates {
     }
 
     pub async fn delete_state(&self, state_id: &str) -> Result<()> {
+        self.ensure_writable(&[state_id.to_string()]).await?;
         match self.pop_state(state_id).await {
             Some(_) => Ok(()),
             None => Err(Error::msg("State ID does not exist!")),
         }
     }
 
+    /// Marks a state as read-only, so it can only be copied or forked.
+    pub async fn mark_readonly(&self, state_id: &str) {
+        self.0.readonly.write().await.insert(state_id.to_string());
+    }
+
+    pub async fn ensure_writable(&self, states: &[String]) -> Result<()> {
+        let readonly = self.0.readonly.read().await;
+        match states.iter().find(|x| readonly.contains(*x)) {
+            Some(id) => Err(Error::msg(format!(
+                "State {id} is read-only, copy it instead!"
+            ))),
+            None => Ok(()),
+        }
+    }
+
     #[inline(always)]
     pub async fn has_state(&self, state_id: &str) -> bool {
         self.0.states.read().await.contains_key(state_id)
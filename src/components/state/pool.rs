@@ -279,6 +279,19 @@ impl InferPool {
         }
     }
 
+    /// Drops the slot of a state without writing it back, so the next request
+    /// loads the state from its backing again.
+    pub async fn discard(&self, state_id: &str) {
+        let mut cache = self.0.cache.write().await;
+        if let Some(index) = cache
+            .iter()
+            .find(|(_, state)| state.get_id() == state_id)
+            .map(|(index, _)| *index)
+        {
+            cache.pop(&index);
+        }
+    }
+
     async fn infer_loop(&self, mut queue: mpsc::Receiver<Vec<InferRequest>>) {
         let mut slots = Slots::new(self.0.batch_size, self.0.pool.clone(), self.0.cache.clone());
 
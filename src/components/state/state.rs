@@ -47,6 +47,10 @@ impl NamedState {
         *(self.0.state.write().await) = AxumBackedState::back_from(pool, from).await.unwrap();
     }
 
+    pub async fn embed(&self, layer: usize) -> Vec<f32> {
+        self.0.state.read().await.embed(layer)
+    }
+
     pub async fn dump(&self, path: PathBuf) -> Result<()> {
         let lock = self.0.state.read().await;
         serde::dump_state(&lock, path).await
@@ -71,6 +75,12 @@ impl NamedState {
         })))
     }
 
+    /// Overwrites the content with the one of another state, keeping the id.
+    pub async fn copy_from(&self, other: &NamedState) {
+        let content = other.0.state.read().await.clone();
+        *self.0.state.write().await = content;
+    }
+
     pub fn clone_shallow(&self, id: String) -> Result<Self> {
         Ok(Self(Arc::new(InnerState {
             id,
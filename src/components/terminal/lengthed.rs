@@ -1,12 +1,12 @@
 use anyhow::{Error, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{app::AppState, components::ComponentDump};
 
 use super::types::Terminal;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LengthedTerminal {
     length: usize,
 }
@@ -21,6 +21,10 @@ impl Terminal for LengthedTerminal {
             length: self.length,
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("lengthed", self)
+    }
 }
 
 pub fn initialize_lenghted(_state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
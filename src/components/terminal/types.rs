@@ -1,6 +1,9 @@
 use anyhow::Result;
+use serde_json::Value;
 use std::fmt::Debug;
 
+use crate::components::ComponentDump;
+
 pub trait Terminal: Send + Sync + Debug {
     /// Determines if the generation should be stopped.
     ///
@@ -22,4 +25,17 @@ pub trait Terminal: Send + Sync + Debug {
     /// the cloned state, it is safe to share internal state by using `Arc`,
     /// etc.
     fn clone(&self) -> Box<dyn Terminal>;
+    /// Dumps the construction params along with the internal state of the `Terminal`,
+    /// so it can be persisted and restored later.
+    ///
+    /// The `type_id` of the dump must be the one the `Terminal` is registered with.
+    fn dump(&self) -> Result<ComponentDump>;
+    /// Restores the internal state from a dump, after the `Terminal` is constructed
+    /// from the dumped params.
+    ///
+    /// By default the `Terminal` has no internal state, so nothing is restored.
+    #[allow(unused_variables)]
+    fn restore(&mut self, state: Value) -> Result<()> {
+        Ok(())
+    }
 }
@@ -1,8 +1,8 @@
 use anyhow::{Error, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{app::AppState, components::ComponentDump};
 
 use super::types::Terminal;
 
@@ -13,7 +13,7 @@ pub struct UntilTerminal {
     cap: Option<usize>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct UntilData {
     until: String,
     cap: Option<usize>,
@@ -33,6 +33,16 @@ impl Terminal for UntilTerminal {
             cap: self.cap.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new(
+            "until",
+            &UntilData {
+                until: self.until.clone(),
+                cap: self.cap,
+            },
+        )
+    }
 }
 
 pub fn intialize_until(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
+
+use super::types::{Transformer, MASKED_LOGIT};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct BanPhrasesData {
+    phrases: Vec<String>,
+    /// Also bans the lowercase, uppercase and capitalized variants.
+    #[serde(default = "BanPhrasesData::default_true")]
+    case_variants: bool,
+    /// Also bans the variants with a leading space.
+    #[serde(default = "BanPhrasesData::default_true")]
+    leading_space: bool,
+}
+
+impl BanPhrasesData {
+    fn default_true() -> bool {
+        true
+    }
+
+    fn variants(&self) -> HashSet<String> {
+        let mut variants = HashSet::new();
+        for phrase in self.phrases.iter() {
+            variants.insert(phrase.clone());
+            if self.case_variants {
+                variants.insert(phrase.to_lowercase());
+                variants.insert(phrase.to_uppercase());
+                let mut chars = phrase.chars();
+                if let Some(first) = chars.next() {
+                    variants.insert(first.to_uppercase().chain(chars).collect());
+                }
+            }
+        }
+        if self.leading_space {
+            let spaced = variants.iter().map(|x| format!(" {x}")).collect::<Vec<_>>();
+            variants.extend(spaced);
+        }
+        variants
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BanPhrasesState {
+    history: VecDeque<u16>,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<u16, usize>,
+    /// Whether a phrase ends at this node.
+    terminal: bool,
+}
+
+/// A trie over the token sequences of banned phrases.
+#[derive(Debug)]
+struct PhraseTrie {
+    nodes: Vec<TrieNode>,
+    /// Length of the longest phrase in tokens.
+    max_length: usize,
+}
+
+impl PhraseTrie {
+    fn new(phrases: impl Iterator<Item = Vec<u16>>) -> Self {
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+            max_length: 0,
+        };
+        for phrase in phrases.filter(|x| !x.is_empty()) {
+            trie.max_length = trie.max_length.max(phrase.len());
+            let mut node = 0;
+            for token in phrase {
+                node = match trie.nodes[node].children.get(&token) {
+                    Some(&child) => child,
+                    None => {
+                        trie.nodes.push(TrieNode::default());
+                        let child = trie.nodes.len() - 1;
+                        trie.nodes[node].children.insert(token, child);
+                        child
+                    }
+                };
+            }
+            trie.nodes[node].terminal = true;
+        }
+        trie
+    }
+
+    /// Walks the trie along the tokens, returning the node reached.
+    fn walk(&self, tokens: impl Iterator<Item = u16>) -> Option<usize> {
+        let mut node = 0;
+        for token in tokens {
+            node = *self.nodes[node].children.get(&token)?;
+        }
+        Some(node)
+    }
+
+    /// Tokens which complete a phrase right after the node.
+    fn completions(&self, node: usize) -> impl Iterator<Item = u16> + '_ {
+        self.nodes[node]
+            .children
+            .iter()
+            .filter(|(_, &child)| self.nodes[child].terminal)
+            .map(|(&token, _)| token)
+    }
+}
+
+/// Bans phrases by masking the token which would complete one of them, so the
+/// tokens of the phrases are still allowed anywhere else.
+#[derive(Debug, Clone)]
+pub struct BanPhrases {
+    data: BanPhrasesData,
+    trie: Arc<PhraseTrie>,
+    /// Recent tokens, only as many as a proper prefix of the longest phrase.
+    history: VecDeque<u16>,
+}
+
+impl BanPhrases {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data: BanPhrasesData = serde_json::from_value(data.ok_or(Error::msg(
+            "Invalid ban phrases data. Example format:{
+                phrases: Vec<String>,
+                case_variants: Option<bool>,
+                leading_space: Option<bool>,
+            }",
+        ))?)?;
+        let phrases = data
+            .variants()
+            .into_iter()
+            .map(|x| state.tokenize(&x.into_bytes()))
+            .collect::<Result<Vec<_>>>()?;
+        let trie = PhraseTrie::new(phrases.into_iter());
+        Ok(Box::new(BanPhrases {
+            data,
+            history: VecDeque::with_capacity(trie.max_length),
+            trie: Arc::new(trie),
+        }))
+    }
+}
+
+impl Transformer for BanPhrases {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        let capacity = self.trie.max_length.saturating_sub(1);
+        for &token in prompt {
+            if self.history.len() == capacity {
+                self.history.pop_front();
+            }
+            if capacity > 0 {
+                self.history.push_back(token);
+            }
+        }
+        Ok(())
+    }
+
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        let mut logits = logits;
+        // Each suffix of the history may be a proper prefix of a phrase,
+        // including the empty one for single token phrases.
+        for start in 0..=self.history.len() {
+            if let Some(node) = self.trie.walk(self.history.range(start..).copied()) {
+                for token in self.trie.completions(node) {
+                    logits[token as usize] = MASKED_LOGIT;
+                }
+            }
+        }
+        logits
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("ban_phrases", &self.data)?.with_state(&BanPhrasesState {
+            history: self.history.clone(),
+        })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let BanPhrasesState { history } = serde_json::from_value(state)?;
+        self.history = history;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(phrases: Vec<Vec<u16>>) -> BanPhrases {
+        let trie = PhraseTrie::new(phrases.into_iter());
+        BanPhrases {
+            data: BanPhrasesData {
+                phrases: Vec::new(),
+                case_variants: false,
+                leading_space: false,
+            },
+            history: VecDeque::with_capacity(trie.max_length),
+            trie: Arc::new(trie),
+        }
+    }
+
+    fn banned(transformer: &BanPhrases) -> Vec<usize> {
+        let logits = transformer.transform(vec![0.0; 8]);
+        (0..logits.len())
+            .filter(|&x| logits[x] == MASKED_LOGIT)
+            .collect()
+    }
+
+    #[test]
+    fn variants_cover_cases_and_spaces() {
+        let data = BanPhrasesData {
+            phrases: vec!["hello World".into()],
+            case_variants: true,
+            leading_space: true,
+        };
+        let variants = data.variants();
+        for variant in ["hello World", "hello world", "HELLO WORLD", "Hello World"] {
+            assert!(variants.contains(variant), "{variant}");
+            assert!(variants.contains(&format!(" {variant}")), "{variant}");
+        }
+        assert_eq!(variants.len(), 8);
+
+        let data = BanPhrasesData {
+            case_variants: false,
+            leading_space: false,
+            ..data
+        };
+        assert_eq!(data.variants(), HashSet::from(["hello World".to_string()]));
+    }
+
+    #[test]
+    fn trie_completes_phrases() {
+        let trie = PhraseTrie::new([vec![1, 2, 3], vec![1, 4], vec![], vec![5]].into_iter());
+        assert_eq!(trie.max_length, 3);
+        let mut completions = trie
+            .completions(trie.walk([1].into_iter()).unwrap())
+            .collect::<Vec<_>>();
+        completions.sort();
+        assert_eq!(completions, vec![4]);
+        let completions = trie.completions(trie.walk([1, 2].into_iter()).unwrap());
+        assert_eq!(completions.collect::<Vec<_>>(), vec![3]);
+        assert_eq!(trie.completions(0).collect::<Vec<_>>(), vec![5]);
+        assert!(trie.walk([2].into_iter()).is_none());
+    }
+
+    #[test]
+    fn only_completing_tokens_are_banned() {
+        let mut transformer = ban(vec![vec![1, 2, 3], vec![4]]);
+        assert_eq!(banned(&transformer), vec![4]);
+        transformer.update(&vec![1, 2]).ok();
+        assert_eq!(banned(&transformer), vec![3, 4]);
+        transformer.update(&vec![3]).ok();
+        assert_eq!(banned(&transformer), vec![4]);
+
+        // Phrases may start anywhere in the history, which only keeps as many
+        // tokens as needed.
+        transformer.update(&vec![1, 1, 2]).ok();
+        assert_eq!(transformer.history, VecDeque::from([1, 2]));
+        assert_eq!(banned(&transformer), vec![3, 4]);
+        transformer.clear();
+        assert_eq!(banned(&transformer), vec![4]);
+    }
+}
@@ -1,56 +1,40 @@
-use std::sync::Arc;
-
-use crate::{app::AppState, components::InferenceInterruption};
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
 use anyhow::{Error, Result};
 use bit_set::BitSet;
-use bnf_sampler::{
-    grammar::Grammar,
-    sampler::{AcceptTokenResult, PossibleTokensResult, Sampler},
-    utils::U8ArrayWrapper,
-    vocabulary::Vocabulary,
-};
-use qp_trie::Trie;
-use rustc_hash::FxHashMap;
-use serde::Deserialize;
+use bnf_sampler::sampler::{AcceptTokenResult, PossibleTokensResult, Sampler};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BNFData {
     grammar: String,
     stack_arena_capacity: usize,
     grammar_stack_arena_capacity: usize,
     start_nonterminal: String,
     stack_to_bytes_cache_enabled: bool,
+    #[serde(default)]
+    syntax: GrammarSyntax,
 }
 
-use super::types::Transformer;
+use super::{
+    grammar_syntax::GrammarSyntax,
+    types::{Transformer, MASKED_LOGIT},
+};
 #[derive(Debug, Clone)]
 pub struct BNFConstraint {
+    data: BNFData,
     sampler: Sampler,
     current_token_ids: BitSet,
+    /// Tokens accepted since the last clear, the parser state can't be dumped
+    /// so it's restored by replaying them.
+    history: Vec<u16>,
 }
 
 impl BNFConstraint {
     pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
-        let vocabulary = state.0.tokenizer.bytes_to_token_index();
-        let token_to_id = Trie::from_iter(
-            vocabulary
-                .iter()
-                .map(|(k, v)| (U8ArrayWrapper(k.clone().into_boxed_slice()), *v as u32)),
-        );
-        let mut id_to_token = FxHashMap::default();
-        id_to_token.extend(vocabulary.iter().map(|(k, v)| (*v as u32, k.clone())));
-        let mut id_to_token_string = FxHashMap::default();
-        id_to_token_string.extend(
-            vocabulary
-                .iter()
-                .map(|(k, v)| (*v as u32, String::from_utf8_lossy(k).to_string())),
-        );
-        let vocabulary = Arc::new(Vocabulary {
-            token_to_id,
-            id_to_token,
-            id_to_token_string,
-        });
         let data = serde_json::from_value::<BNFData>(data.ok_or(Error::msg(
             "Invalid BNFData. Example format:{
                 grammar: String,
@@ -58,16 +42,19 @@ impl BNFConstraint {
                 grammar_stack_arena_capacity: usize,
                 start_nonterminal: String,
                 stack_to_bytes_cache_enabled: bool,
+                syntax: Option<\"bnf\" | \"gbnf\" | \"ebnf\">,
             }",
         ))?)?;
+        let grammar = data.syntax.translate(&data.grammar)?;
+        let grammars = &state.0.grammars;
         let mut sampler = Sampler::new(
-            Grammar::new(
-                &data.grammar,
-                vocabulary.clone(),
+            grammars.get_or_compile(
+                &grammar,
+                &data.start_nonterminal,
                 data.grammar_stack_arena_capacity,
             )?,
-            data.start_nonterminal,
-            vocabulary,
+            data.start_nonterminal.clone(),
+            grammars.vocabulary(),
             data.stack_arena_capacity,
             data.stack_to_bytes_cache_enabled,
         )?;
@@ -76,8 +63,10 @@ impl BNFConstraint {
             _ => unreachable!(),
         };
         Ok(Box::new(BNFConstraint {
+            data,
             sampler,
             current_token_ids,
+            history: Vec::new(),
         }))
     }
 }
@@ -90,13 +79,16 @@ impl Transformer for BNFConstraint {
                 .accept_a_token(Some(*token_id as u32))
                 .map_err(InferenceInterruption::Error)?
             {
-                AcceptTokenResult::End => return Result::Err(InferenceInterruption::Exhaustion),
+                AcceptTokenResult::End => {
+                    self.history.push(*token_id);
+                    return Result::Err(InferenceInterruption::Exhaustion);
+                }
                 AcceptTokenResult::Failed => {
                     return Result::Err(InferenceInterruption::Error(anyhow::anyhow!(
                         "Token {token_id} is rejected by BNF schema."
                     )))
                 }
-                AcceptTokenResult::Continue => {}
+                AcceptTokenResult::Continue => self.history.push(*token_id),
             }
         }
         self.current_token_ids = match self
@@ -114,13 +106,14 @@ impl Transformer for BNFConstraint {
         let mut logits = logits;
         for (i, logit) in logits.iter_mut().enumerate() {
             if !self.current_token_ids.contains(i) {
-                *logit = f32::MIN;
+                *logit = MASKED_LOGIT;
             }
         }
         logits
     }
 
     fn clear(&mut self) {
+        self.history.clear();
         self.sampler.reset();
         self.current_token_ids = match self.sampler.all_possible_next_tokens(None).unwrap() {
             PossibleTokensResult::Continue(token_ids) => token_ids.clone(),
@@ -131,4 +124,17 @@ impl Transformer for BNFConstraint {
     fn clone(&self) -> Box<dyn Transformer> {
         Box::new(Clone::clone(self))
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("bnf_grammar", &self.data)?.with_state(&self.history)
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let history: Vec<u16> = serde_json::from_value(state)?;
+        self.clear();
+        match self.update(&history) {
+            Ok(_) | Err(InferenceInterruption::Exhaustion) => Ok(()),
+            Err(InferenceInterruption::Error(e)) => Err(e),
+        }
+    }
 }
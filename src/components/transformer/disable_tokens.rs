@@ -1,15 +1,13 @@
 use anyhow::{Error, Result};
 use ndarray::Array1;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{app::AppState, components::ComponentDump};
 
-use super::types::Transformer;
+use super::types::{Transformer, MASKED_LOGIT};
 
-const DISABLED: f32 = -1e30;
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DisableTokensData {
     tokens: Vec<u16>,
 }
@@ -29,6 +27,21 @@ impl Transformer for DisableTokens {
             tokens: self.tokens.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new(
+            "disable_token",
+            &DisableTokensData {
+                tokens: self
+                    .tokens
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &x)| x == MASKED_LOGIT)
+                    .map(|(token, _)| token as u16)
+                    .collect(),
+            },
+        )
+    }
 }
 
 pub fn initialize_disable(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
@@ -37,7 +50,7 @@ pub fn initialize_disable(_state: AppState, data: Option<Value>) -> Result<Box<d
         .map(|data| serde_json::from_value(data))??;
     let mut tokens_offset = Array1::zeros(65536);
     for token in tokens {
-        tokens_offset[token as usize] = DISABLED
+        tokens_offset[token as usize] = MASKED_LOGIT
     }
     Ok(Box::new(DisableTokens {
         tokens: tokens_offset,
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
+
+use super::types::Transformer;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DryData {
+    multiplier: f32,
+    #[serde(default = "DryData::default_base")]
+    base: f32,
+    #[serde(default = "DryData::default_allowed_length")]
+    allowed_length: usize,
+    #[serde(default = "DryData::default_sequence_breakers")]
+    sequence_breakers: Vec<String>,
+    /// Only the most recent tokens are searched for repetitions if set.
+    range: Option<usize>,
+}
+
+impl DryData {
+    fn default_base() -> f32 {
+        1.75
+    }
+
+    fn default_allowed_length() -> usize {
+        2
+    }
+
+    fn default_sequence_breakers() -> Vec<String> {
+        ["\n", ":", "\"", "*"].map(String::from).to_vec()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DryState {
+    history: Vec<u16>,
+}
+
+/// Penalizes tokens which would extend a sequence that has appeared in the
+/// history, growing exponentially by the length of the repetition.
+#[derive(Debug, Clone)]
+pub struct Dry {
+    data: DryData,
+    /// Tokens of the sequence breakers, a repetition never spans across them.
+    breakers: HashSet<u16>,
+    history: Vec<u16>,
+}
+
+impl Dry {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data: DryData = serde_json::from_value(data.ok_or(Error::msg(
+            "Invalid DRY data. Example format:{
+                multiplier: f32,
+                base: Option<f32>,
+                allowed_length: Option<usize>,
+                sequence_breakers: Option<Vec<String>>,
+                range: Option<usize>,
+            }",
+        ))?)?;
+        if !data.multiplier.is_finite() {
+            return Err(Error::msg("data.multiplier must be finite!"));
+        }
+        if !(data.base >= 1.0 && data.base.is_finite()) {
+            return Err(Error::msg("data.base must be finite and not less than 1!"));
+        }
+        if data.allowed_length == 0 {
+            return Err(Error::msg("data.allowed_length must be larger than 0!"));
+        }
+        let mut breakers = HashSet::new();
+        for breaker in data.sequence_breakers.iter() {
+            breakers.extend(state.tokenize(&breaker.as_bytes().to_vec())?);
+        }
+        Ok(Box::new(Dry {
+            data,
+            breakers,
+            history: Vec::new(),
+        }))
+    }
+
+    /// Finds the longest repetition each token would extend, keyed by the token.
+    fn match_lengths(&self) -> HashMap<u16, usize> {
+        let mut lengths = HashMap::new();
+        let history = match self.data.range {
+            Some(range) => &self.history[self.history.len().saturating_sub(range)..],
+            None => &self.history[..],
+        };
+        let Some((&last, _)) = history.split_last() else {
+            return lengths;
+        };
+        if self.breakers.contains(&last) {
+            return lengths;
+        }
+
+        let end = history.len() - 1;
+        for i in (0..end).filter(|&i| history[i] == last) {
+            let mut length = 1;
+            while length <= i
+                && history[i - length] == history[end - length]
+                && !self.breakers.contains(&history[i - length])
+            {
+                length += 1;
+            }
+            let entry = lengths.entry(history[i + 1]).or_insert(0);
+            *entry = (*entry).max(length);
+        }
+        lengths
+    }
+}
+
+impl Transformer for Dry {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        self.history.extend(prompt);
+        Ok(())
+    }
+
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        let mut logits = logits;
+        for (token, length) in self.match_lengths() {
+            if length >= self.data.allowed_length {
+                let exponent = (length - self.data.allowed_length).min(i32::MAX as usize) as i32;
+                // Long repetitions overflow the growth, which is clamped so the
+                // logits stay finite.
+                let growth = self.data.base.powi(exponent).min(f32::MAX);
+                let penalty = (self.data.multiplier * growth).clamp(-f32::MAX, f32::MAX);
+                logits[token as usize] =
+                    (logits[token as usize] - penalty).clamp(-f32::MAX, f32::MAX);
+            }
+        }
+        logits
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("dry", &self.data)?.with_state(&DryState {
+            history: self.history.clone(),
+        })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let DryState { history } = serde_json::from_value(state)?;
+        self.history = history;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dry(multiplier: f32) -> Dry {
+        Dry {
+            data: DryData {
+                multiplier,
+                base: DryData::default_base(),
+                allowed_length: DryData::default_allowed_length(),
+                sequence_breakers: Vec::new(),
+                range: None,
+            },
+            breakers: HashSet::new(),
+            history: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn repetition_is_penalized() {
+        let mut dry = dry(1.0);
+        dry.update(&vec![1, 2, 3, 4, 1, 2, 3]).ok();
+        let logits = dry.transform(vec![0.0; 8]);
+        // 1 2 3 would continue with 4, a repetition of length 3.
+        assert_eq!(logits[4], -DryData::default_base());
+        assert!(logits.iter().enumerate().all(|(i, &x)| i == 4 || x == 0.0));
+    }
+
+    #[test]
+    fn long_repetition_stays_finite() {
+        for multiplier in [0.0, 1.0, 1e30] {
+            let mut dry = dry(multiplier);
+            let repeated = (0..1000).map(|x| x % 7).collect::<Vec<u16>>();
+            dry.update(&repeated).ok();
+            let logits = dry.transform(vec![0.0; 8]);
+            assert!(logits.iter().all(|x| x.is_finite()), "{multiplier}");
+        }
+    }
+}
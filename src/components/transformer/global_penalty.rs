@@ -1,13 +1,16 @@
 use anyhow::{Error, Result};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{app::AppState, components::InferenceInterruption};
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
 
-use super::types::{penalty_transform, PenaltyMode, Transformer};
+use super::types::{penalty_transform, PenaltyMode, SparseRecord, Transformer};
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 struct PenaltyData {
     alpha_occurrence: f32,
     alpha_presence: f32,
@@ -15,6 +18,12 @@ struct PenaltyData {
     mode: PenaltyMode,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct PenaltyState {
+    record: SparseRecord,
+    presence: SparseRecord,
+}
+
 #[derive(Debug)]
 pub struct GlobalPenalty {
     data: PenaltyData,
@@ -57,6 +66,28 @@ impl Transformer for GlobalPenalty {
             record: self.record.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        let default = match self.data.mode {
+            PenaltyMode::Subtract => 0.0,
+            PenaltyMode::Divide => 1.0,
+        };
+        ComponentDump::new("global_penalty", &self.data)?.with_state(&PenaltyState {
+            record: SparseRecord::new(&self.record, default),
+            presence: SparseRecord::new(&self.presence, default),
+        })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let PenaltyState { record, presence } = serde_json::from_value(state)?;
+        let default = match self.data.mode {
+            PenaltyMode::Subtract => 0.0,
+            PenaltyMode::Divide => 1.0,
+        };
+        self.record = record.into_dense(default);
+        self.presence = presence.into_dense(default);
+        Ok(())
+    }
 }
 
 pub fn initialize_global(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
@@ -0,0 +1,181 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use bnf_sampler::{grammar::Grammar, utils::U8ArrayWrapper, vocabulary::Vocabulary};
+use lru::LruCache;
+use qp_trie::Trie;
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use web_rwkv::tokenizer::Tokenizer;
+
+/// Estimated bytes of a compiled grammar per char of its source.
+const GRAMMAR_BYTES_PER_CHAR: usize = 64;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct GrammarKey {
+    grammar: String,
+    start_nonterminal: String,
+    /// Compiling may fail with a small arena, so grammars compiled with
+    /// different capacities are not shared.
+    grammar_stack_arena_capacity: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GrammarCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    /// Estimated bytes held by the cached grammars.
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Cached grammars along with their estimated bytes.
+struct Entries {
+    grammars: LruCache<GrammarKey, (Arc<Grammar>, usize)>,
+    bytes: usize,
+}
+
+/// Vocabulary of the tokenizer built once, and compiled BNF grammars kept in an
+/// LRU cache, so creating `bnf_grammar` transformers doesn't recompile them.
+///
+/// The cache is bounded by both the count of grammars and their estimated bytes.
+pub struct GrammarCache {
+    vocabulary: Arc<Vocabulary>,
+    /// Disabled if the capacity is 0.
+    grammars: Option<Mutex<Entries>>,
+    max_bytes: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl GrammarCache {
+    pub fn new(tokenizer: &Tokenizer, capacity: usize, max_bytes: usize) -> Self {
+        let vocabulary = tokenizer.bytes_to_token_index();
+        let token_to_id = Trie::from_iter(
+            vocabulary
+                .iter()
+                .map(|(k, v)| (U8ArrayWrapper(k.clone().into_boxed_slice()), *v as u32)),
+        );
+        let mut id_to_token = FxHashMap::default();
+        id_to_token.extend(vocabulary.iter().map(|(k, v)| (*v as u32, k.clone())));
+        let mut id_to_token_string = FxHashMap::default();
+        id_to_token_string.extend(
+            vocabulary
+                .iter()
+                .map(|(k, v)| (*v as u32, String::from_utf8_lossy(k).to_string())),
+        );
+        Self {
+            vocabulary: Arc::new(Vocabulary {
+                token_to_id,
+                id_to_token,
+                id_to_token_string,
+            }),
+            grammars: NonZeroUsize::new(capacity).map(|x| {
+                Mutex::new(Entries {
+                    grammars: LruCache::new(x),
+                    bytes: 0,
+                })
+            }),
+            max_bytes,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn vocabulary(&self) -> Arc<Vocabulary> {
+        self.vocabulary.clone()
+    }
+
+    /// Gets the compiled grammar from the cache, or compiles and caches it.
+    pub fn get_or_compile(
+        &self,
+        grammar: &str,
+        start_nonterminal: &str,
+        grammar_stack_arena_capacity: usize,
+    ) -> Result<Arc<Grammar>> {
+        let key = GrammarKey {
+            grammar: grammar.to_string(),
+            start_nonterminal: start_nonterminal.to_string(),
+            grammar_stack_arena_capacity,
+        };
+        if let Some(entries) = &self.grammars {
+            if let Some((compiled, _)) = entries.lock().unwrap().grammars.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(compiled.clone());
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Compiled without holding the lock, so other grammars are not blocked.
+        let compiled = Grammar::new(
+            grammar,
+            self.vocabulary.clone(),
+            grammar_stack_arena_capacity,
+        )?;
+        let bytes = self.estimate_bytes(grammar);
+        if let Some(entries) = self.grammars.as_ref().filter(|_| bytes <= self.max_bytes) {
+            let mut entries = entries.lock().unwrap();
+            entries.bytes += bytes;
+            if let Some((_, (_, evicted))) = entries.grammars.push(key, (compiled.clone(), bytes)) {
+                entries.bytes -= evicted;
+            }
+            while entries.bytes > self.max_bytes {
+                let Some((_, (_, evicted))) = entries.grammars.pop_lru() else {
+                    break;
+                };
+                entries.bytes -= evicted;
+            }
+        }
+        Ok(compiled)
+    }
+
+    /// A rough estimate of the memory held by a compiled grammar. Terminals are
+    /// kept in a trie about the size of the source, while each `any!` or
+    /// `except!` holds a token set over the whole vocabulary.
+    fn estimate_bytes(&self, grammar: &str) -> usize {
+        let token_sets = grammar.matches("<any!>").count() + grammar.matches("<except!").count();
+        let token_set_bytes = self.vocabulary.id_to_token.len() / 8;
+        grammar.len() * GRAMMAR_BYTES_PER_CHAR + token_sets * token_set_bytes
+    }
+
+    pub fn stats(&self) -> GrammarCacheStats {
+        let (entries, capacity, bytes) = match &self.grammars {
+            Some(entries) => {
+                let entries = entries.lock().unwrap();
+                (
+                    entries.grammars.len(),
+                    entries.grammars.cap().get(),
+                    entries.bytes,
+                )
+            }
+            None => (0, 0, 0),
+        };
+        GrammarCacheStats {
+            entries,
+            capacity,
+            bytes,
+            max_bytes: self.max_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Removes all compiled grammars, returns the stats before clearing.
+    pub fn clear(&self) -> GrammarCacheStats {
+        let stats = self.stats();
+        if let Some(entries) = &self.grammars {
+            let mut entries = entries.lock().unwrap();
+            entries.grammars.clear();
+            entries.bytes = 0;
+        }
+        stats
+    }
+}
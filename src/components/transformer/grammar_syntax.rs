@@ -0,0 +1,1001 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Max count of characters a character class can expand to.
+const MAX_CLASS_CHARS: usize = 1024;
+/// Max count of a bounded repetition, which is expanded into as many symbols.
+const MAX_REPEAT: usize = 256;
+/// Sequences with more alternatives are moved into helper rules, so optional
+/// items don't multiply the alternatives without bound.
+const MAX_INLINE_ALTERNATIVES: usize = 4;
+
+/// Syntax of the grammar given to the `bnf_grammar` transformer.
+///
+/// Grammars other than `bnf` are translated into the dialect of `bnf_sampler`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrammarSyntax {
+    /// The dialect of `bnf_sampler`, used as is.
+    #[default]
+    Bnf,
+    /// The GBNF dialect of llama.cpp, like `root ::= [a-z]+ ("," [a-z]+)*`.
+    Gbnf,
+    /// The EBNF dialect of Lark, like `start: WORD ("," WORD)*`.
+    Ebnf,
+}
+
+impl GrammarSyntax {
+    pub fn translate(&self, grammar: &str) -> Result<String> {
+        match self {
+            GrammarSyntax::Bnf => Ok(grammar.to_string()),
+            _ => {
+                let rules = Parser::new(grammar, *self).parse()?;
+                Emitter::new(grammar).emit(rules)
+            }
+        }
+    }
+
+    fn definition(&self) -> &'static str {
+        match self {
+            GrammarSyntax::Ebnf => ":",
+            _ => "::=",
+        }
+    }
+
+    fn comment(&self) -> &'static str {
+        match self {
+            GrammarSyntax::Ebnf => "//",
+            _ => "#",
+        }
+    }
+
+    fn is_name_char(&self, c: char) -> bool {
+        match self {
+            GrammarSyntax::Gbnf => c.is_ascii_alphanumeric() || c == '-' || c == '_',
+            _ => c.is_ascii_alphanumeric() || c == '_',
+        }
+    }
+}
+
+/// Formats an error with the line and column of the byte position.
+fn positioned(source: &str, pos: usize, message: impl std::fmt::Display) -> Error {
+    let before = &source[..pos];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    Error::msg(format!(
+        "Grammar error at line {line}, column {column}: {message}"
+    ))
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(String),
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+        pos: usize,
+    },
+    /// Any character, which is only in GBNF.
+    Any(usize),
+    Ref(String, usize),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat {
+        expr: Box<Expr>,
+        min: usize,
+        max: Option<usize>,
+        pos: usize,
+    },
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    expr: Expr,
+    pos: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+    syntax: GrammarSyntax,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, syntax: GrammarSyntax) -> Self {
+        Self {
+            source,
+            pos: 0,
+            syntax,
+        }
+    }
+
+    fn error(&self, pos: usize, message: impl std::fmt::Display) -> Error {
+        positioned(self.source, pos, message)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let matched = self.rest().starts_with(token);
+        if matched {
+            self.pos += token.len();
+        }
+        matched
+    }
+
+    fn expect(&mut self, token: &str, pos: usize, message: &str) -> Result<()> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(self.error(pos, message)),
+        }
+    }
+
+    /// Skips whitespaces, line breaks and comments.
+    fn skip(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with(self.syntax.comment()) {
+                break;
+            }
+            self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let len = self
+            .rest()
+            .find(|c| !self.syntax.is_name_char(c))
+            .unwrap_or(self.rest().len());
+        let name = &self.rest()[..len];
+        self.pos += len;
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    /// Whether a new rule is defined at the position, which ends the last one.
+    fn at_rule_start(&self) -> bool {
+        let mut parser = self.clone();
+        if parser.syntax == GrammarSyntax::Ebnf {
+            let _ = parser.eat("?") || parser.eat("!");
+        }
+        if parser.name().is_none() {
+            return false;
+        }
+        parser.skip();
+        parser.rest().starts_with(parser.syntax.definition())
+    }
+
+    fn parse(mut self) -> Result<Vec<Rule>> {
+        let mut rules = Vec::new();
+        self.skip();
+        while self.peek().is_some() {
+            let pos = self.pos;
+            if self.syntax == GrammarSyntax::Ebnf {
+                if self.peek() == Some('%') {
+                    return Err(self.error(pos, "Directives like %import are not supported"));
+                }
+                let _ = self.eat("?") || self.eat("!");
+            }
+            let name = self
+                .name()
+                .ok_or_else(|| self.error(self.pos, "Expected a rule name"))?;
+            if self.syntax == GrammarSyntax::Ebnf && self.peek() == Some('.') {
+                return Err(self.error(self.pos, "Rule priorities are not supported"));
+            }
+            self.skip();
+            let definition = self.syntax.definition();
+            self.expect(
+                definition,
+                self.pos,
+                &format!("Expected `{definition}` after the rule name"),
+            )?;
+            let expr = self.alternation()?;
+            rules.push(Rule { name, expr, pos });
+            self.skip();
+        }
+        if rules.is_empty() {
+            return Err(self.error(0, "The grammar has no rule"));
+        }
+        Ok(rules)
+    }
+
+    fn alternation(&mut self) -> Result<Expr> {
+        let mut options = vec![self.sequence()?];
+        self.skip();
+        while self.eat("|") {
+            options.push(self.sequence()?);
+            self.skip();
+        }
+        Ok(match options.len() {
+            1 => options.pop().unwrap(),
+            _ => Expr::Alt(options),
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Expr> {
+        let mut items = Vec::new();
+        loop {
+            self.skip();
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some(']') if self.syntax == GrammarSyntax::Ebnf => break,
+                _ if self.at_rule_start() => break,
+                _ => {}
+            }
+            let item = self.atom()?;
+            items.push(self.postfix(item)?);
+        }
+        Ok(Expr::Seq(items))
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let pos = self.pos;
+        match self.peek() {
+            Some('"') => self.literal(),
+            Some('[') if self.syntax == GrammarSyntax::Gbnf => self.class(),
+            Some('[') => {
+                self.bump();
+                let expr = self.alternation()?;
+                self.expect("]", pos, "Unclosed optional group")?;
+                Ok(Expr::Repeat {
+                    expr: Box::new(expr),
+                    min: 0,
+                    max: Some(1),
+                    pos,
+                })
+            }
+            Some('(') => {
+                self.bump();
+                let expr = self.alternation()?;
+                self.expect(")", pos, "Unclosed group")?;
+                Ok(expr)
+            }
+            Some('.') if self.syntax == GrammarSyntax::Gbnf => {
+                self.bump();
+                Ok(Expr::Any(pos))
+            }
+            Some('/') if self.syntax == GrammarSyntax::Ebnf => {
+                Err(self.error(pos, "Regex terminals are not supported"))
+            }
+            Some(c) if self.syntax.is_name_char(c) => Ok(Expr::Ref(self.name().unwrap(), pos)),
+            Some(c) => Err(self.error(pos, format!("Unexpected `{c}`"))),
+            None => Err(self.error(pos, "Unexpected end of the grammar")),
+        }
+    }
+
+    fn postfix(&mut self, expr: Expr) -> Result<Expr> {
+        let mut expr = expr;
+        loop {
+            self.skip();
+            let pos = self.pos;
+            let (min, max) = match self.peek() {
+                Some('?') if self.syntax == GrammarSyntax::Ebnf && self.at_rule_start() => break,
+                Some('*' | '+' | '?') => match self.bump() {
+                    Some('*') => (0, None),
+                    Some('+') => (1, None),
+                    _ => (0, Some(1)),
+                },
+                Some('{') if self.syntax == GrammarSyntax::Gbnf => self.braces()?,
+                Some('~') if self.syntax == GrammarSyntax::Ebnf => self.tilde()?,
+                _ => break,
+            };
+            if max.is_some_and(|max| max < min) {
+                return Err(self.error(pos, "The max repetition is less than the min"));
+            }
+            if min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                return Err(self.error(
+                    pos,
+                    format!("Repetitions can't be more than {MAX_REPEAT} times"),
+                ));
+            }
+            expr = Expr::Repeat {
+                expr: Box::new(expr),
+                min,
+                max,
+                pos,
+            };
+        }
+        Ok(expr)
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest().len());
+        let number = self.rest()[..len]
+            .parse()
+            .map_err(|_| self.error(self.pos, "Expected a number"))?;
+        self.pos += len;
+        Ok(number)
+    }
+
+    /// Parses `{m}`, `{m,}` or `{m,n}` in GBNF.
+    fn braces(&mut self) -> Result<(usize, Option<usize>)> {
+        let pos = self.pos;
+        self.bump();
+        self.skip();
+        let min = self.number()?;
+        self.skip();
+        let max = match self.eat(",") {
+            true => {
+                self.skip();
+                match self.peek() {
+                    Some('}') => None,
+                    _ => Some(self.number()?),
+                }
+            }
+            false => Some(min),
+        };
+        self.skip();
+        self.expect("}", pos, "Unclosed repetition")?;
+        Ok((min, max))
+    }
+
+    /// Parses `~n` or `~m..n` in EBNF.
+    fn tilde(&mut self) -> Result<(usize, Option<usize>)> {
+        self.bump();
+        self.skip();
+        let min = self.number()?;
+        let max = match self.eat("..") {
+            true => self.number()?,
+            false => min,
+        };
+        Ok((min, Some(max)))
+    }
+
+    fn escape(&mut self, pos: usize) -> Result<char> {
+        let hex = |parser: &mut Self, len: usize| {
+            let digits = parser.rest().get(..len).unwrap_or_default();
+            let c = u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| parser.error(pos, "Invalid escape"))?;
+            parser.pos += len;
+            Ok(c)
+        };
+        match self.bump() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('x') => hex(self, 2),
+            Some('u') => hex(self, 4),
+            Some('U') => hex(self, 8),
+            Some(c @ ('\\' | '"' | '\'' | '[' | ']' | '-' | '^' | '/')) => Ok(c),
+            _ => Err(self.error(pos, "Invalid escape")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let pos = self.pos;
+        self.bump();
+        let mut string = String::new();
+        loop {
+            let escape_pos = self.pos;
+            match self.bump() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(self.escape(escape_pos)?),
+                Some(c) => string.push(c),
+                None => return Err(self.error(pos, "Unclosed string")),
+            }
+        }
+    }
+
+    /// Parses a string, which in EBNF may also be a range like `"a".."z"`, or
+    /// case-insensitive like `"select"i`.
+    fn literal(&mut self) -> Result<Expr> {
+        let pos = self.pos;
+        let string = self.string()?;
+        if self.syntax != GrammarSyntax::Ebnf {
+            return Ok(Expr::Literal(string));
+        }
+        if self.eat("..") {
+            let end = match self.peek() {
+                Some('"') => self.string()?,
+                _ => return Err(self.error(self.pos, "Expected a string after `..`")),
+            };
+            let mut chars = string.chars().chain(end.chars());
+            return match (chars.next(), chars.next(), chars.next()) {
+                (Some(from), Some(to), None) if from <= to => Ok(Expr::Class {
+                    negated: false,
+                    ranges: vec![(from, to)],
+                    pos,
+                }),
+                _ => Err(self.error(pos, "A range must be between two ordered characters")),
+            };
+        }
+        let mut rest = self.rest().chars();
+        if rest.next() == Some('i') && !rest.next().is_some_and(|c| self.syntax.is_name_char(c)) {
+            self.bump();
+            return Ok(Expr::Seq(
+                string
+                    .chars()
+                    .map(|c| {
+                        let mut ranges = c
+                            .to_lowercase()
+                            .chain(c.to_uppercase())
+                            .map(|x| (x, x))
+                            .collect::<Vec<_>>();
+                        ranges.dedup();
+                        Expr::Class {
+                            negated: false,
+                            ranges,
+                            pos,
+                        }
+                    })
+                    .collect(),
+            ));
+        }
+        Ok(Expr::Literal(string))
+    }
+
+    /// Parses a character class like `[^a-z0-9_]` in GBNF.
+    fn class(&mut self) -> Result<Expr> {
+        let pos = self.pos;
+        self.bump();
+        let negated = self.eat("^");
+        let mut ranges = Vec::new();
+        loop {
+            let char_pos = self.pos;
+            let from = match self.bump() {
+                Some(']') => break,
+                Some('\\') => self.escape(char_pos)?,
+                Some(c) => c,
+                None => return Err(self.error(pos, "Unclosed character class")),
+            };
+            let to = match self.rest().starts_with('-') && !self.rest().starts_with("-]") {
+                true => {
+                    self.bump();
+                    let to_pos = self.pos;
+                    match self.bump() {
+                        Some('\\') => self.escape(to_pos)?,
+                        Some(c) => c,
+                        None => return Err(self.error(pos, "Unclosed character class")),
+                    }
+                }
+                false => from,
+            };
+            if to < from {
+                return Err(self.error(char_pos, "The range is out of order"));
+            }
+            ranges.push((from, to));
+        }
+        if ranges.is_empty() {
+            return Err(self.error(pos, "Empty character class"));
+        }
+        Ok(Expr::Class {
+            negated,
+            ranges,
+            pos,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Symbol {
+    Terminal(String),
+    Nonterminal(String),
+}
+
+impl Symbol {
+    fn to_bnf(&self) -> String {
+        match self {
+            Symbol::Nonterminal(name) => format!("<{name}>"),
+            Symbol::Terminal(text) => {
+                let mut escaped = String::new();
+                for c in text.chars() {
+                    match c {
+                        '\\' => escaped.push_str("\\\\"),
+                        '\'' => escaped.push_str("\\x27"),
+                        '\n' => escaped.push_str("\\n"),
+                        '\r' => escaped.push_str("\\r"),
+                        '\t' => escaped.push_str("\\t"),
+                        c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => escaped.push(c),
+                    }
+                }
+                format!("'{escaped}'")
+            }
+        }
+    }
+}
+
+/// An expression lowered into non-empty alternatives of symbols, since empty
+/// terminals are not reliable in `bnf_sampler`. Whether it may match nothing
+/// is tracked aside.
+#[derive(Debug, Clone)]
+struct Lowered {
+    alternatives: Vec<Vec<Symbol>>,
+    nullable: bool,
+}
+
+impl Lowered {
+    fn empty() -> Self {
+        Self {
+            alternatives: Vec::new(),
+            nullable: true,
+        }
+    }
+}
+
+/// Emits parsed rules in the dialect of `bnf_sampler`.
+struct Emitter<'a> {
+    source: &'a str,
+    nullable: HashMap<String, bool>,
+    rules: Vec<(String, Vec<Vec<Symbol>>)>,
+    helper_rules: Vec<(String, Vec<Vec<Symbol>>)>,
+    /// Name of the rule being lowered, for naming helper rules.
+    current: String,
+    helpers: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            nullable: HashMap::new(),
+            rules: Vec::new(),
+            helper_rules: Vec::new(),
+            current: String::new(),
+            helpers: 0,
+        }
+    }
+
+    fn emit(mut self, rules: Vec<Rule>) -> Result<String> {
+        // Multiple definitions of a rule are merged as alternatives.
+        let mut merged: Vec<Rule> = Vec::new();
+        for rule in rules {
+            match merged.iter_mut().find(|x| x.name == rule.name) {
+                Some(existed) => {
+                    let expr = std::mem::replace(&mut existed.expr, Expr::Seq(Vec::new()));
+                    existed.expr = Expr::Alt(vec![expr, rule.expr]);
+                }
+                None => merged.push(rule),
+            }
+        }
+        for rule in merged.iter() {
+            self.check_refs(&rule.expr, &merged)?;
+            self.nullable.insert(rule.name.clone(), false);
+        }
+        loop {
+            let mut changed = false;
+            for rule in merged.iter() {
+                if !self.nullable[&rule.name] && self.is_nullable(&rule.expr) {
+                    self.nullable.insert(rule.name.clone(), true);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        for rule in merged.iter() {
+            self.current = rule.name.clone();
+            let lowered = self.lower(&rule.expr)?;
+            if lowered.alternatives.is_empty() {
+                return Err(positioned(
+                    self.source,
+                    rule.pos,
+                    format!("Rule `{}` can only match nothing", rule.name),
+                ));
+            }
+            self.rules.push((rule.name.clone(), lowered.alternatives));
+        }
+        Ok(self
+            .rules
+            .iter()
+            .chain(self.helper_rules.iter())
+            .map(|(name, alternatives)| {
+                let alternatives = alternatives
+                    .iter()
+                    .map(|x| x.iter().map(Symbol::to_bnf).collect::<Vec<_>>().join(" "))
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                format!("<{name}> ::= {alternatives}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn check_refs(&self, expr: &Expr, rules: &[Rule]) -> Result<()> {
+        match expr {
+            Expr::Ref(name, pos) if !rules.iter().any(|x| &x.name == name) => Err(positioned(
+                self.source,
+                *pos,
+                format!("Rule `{name}` is not defined"),
+            )),
+            Expr::Seq(items) | Expr::Alt(items) => {
+                items.iter().try_for_each(|x| self.check_refs(x, rules))
+            }
+            Expr::Repeat { expr, .. } => self.check_refs(expr, rules),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_nullable(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Literal(text) => text.is_empty(),
+            Expr::Class { .. } | Expr::Any(_) => false,
+            Expr::Ref(name, _) => self.nullable[name],
+            Expr::Seq(items) => items.iter().all(|x| self.is_nullable(x)),
+            Expr::Alt(items) => items.iter().any(|x| self.is_nullable(x)),
+            Expr::Repeat { expr, min, .. } => *min == 0 || self.is_nullable(expr),
+        }
+    }
+
+    fn helper_name(&mut self) -> String {
+        self.helpers += 1;
+        format!("{}#{}", self.current, self.helpers)
+    }
+
+    /// Defines a helper rule, returns the symbol referring to it.
+    fn helper(&mut self, alternatives: Vec<Vec<Symbol>>) -> Symbol {
+        let name = self.helper_name();
+        self.helper_rules.push((name.clone(), alternatives));
+        Symbol::Nonterminal(name)
+    }
+
+    /// Turns the alternatives into at most one sequence of symbols.
+    fn single(&mut self, lowered: Lowered) -> Lowered {
+        match lowered.alternatives.len() {
+            0 | 1 => lowered,
+            _ => Lowered {
+                alternatives: vec![vec![self.helper(lowered.alternatives)]],
+                nullable: lowered.nullable,
+            },
+        }
+    }
+
+    /// Turns the alternatives into exactly one symbol.
+    fn symbol(&mut self, alternatives: Vec<Vec<Symbol>>) -> Symbol {
+        match alternatives.as_slice() {
+            [alternative] if alternative.len() == 1 => alternative[0].clone(),
+            _ => self.helper(alternatives),
+        }
+    }
+
+    fn lower(&mut self, expr: &Expr) -> Result<Lowered> {
+        match expr {
+            Expr::Literal(text) if text.is_empty() => Ok(Lowered::empty()),
+            Expr::Literal(text) => Ok(Lowered {
+                alternatives: vec![vec![Symbol::Terminal(text.clone())]],
+                nullable: false,
+            }),
+            Expr::Class {
+                negated: false,
+                ranges,
+                pos,
+            } => Ok(Lowered {
+                alternatives: self
+                    .class_chars(ranges, *pos)?
+                    .into_iter()
+                    .map(|c| vec![Symbol::Terminal(c.to_string())])
+                    .collect(),
+                nullable: false,
+            }),
+            Expr::Class { pos, .. } | Expr::Any(pos) => Err(positioned(
+                self.source,
+                *pos,
+                "Negated character classes and `.` are only supported with `*` or `+`",
+            )),
+            Expr::Ref(name, _) => Ok(Lowered {
+                alternatives: vec![vec![Symbol::Nonterminal(name.clone())]],
+                nullable: self.nullable[name],
+            }),
+            Expr::Alt(options) => {
+                let mut lowered = Lowered {
+                    alternatives: Vec::new(),
+                    nullable: false,
+                };
+                for option in options {
+                    let option = self.lower(option)?;
+                    lowered.alternatives.extend(option.alternatives);
+                    lowered.nullable |= option.nullable;
+                }
+                lowered.alternatives.dedup();
+                Ok(lowered)
+            }
+            Expr::Seq(items) => {
+                let mut lowered = Lowered::empty();
+                for item in items {
+                    let item = self.lower(item)?;
+                    let item = self.single(item);
+                    let mut alternatives = Vec::new();
+                    for head in lowered.alternatives.iter() {
+                        for tail in item.alternatives.iter() {
+                            alternatives.push([head.clone(), tail.clone()].concat());
+                        }
+                    }
+                    if lowered.nullable {
+                        alternatives.extend(item.alternatives.iter().cloned());
+                    }
+                    if item.nullable {
+                        alternatives.extend(lowered.alternatives.iter().cloned());
+                    }
+                    lowered = Lowered {
+                        alternatives,
+                        nullable: lowered.nullable && item.nullable,
+                    };
+                    if lowered.alternatives.len() > MAX_INLINE_ALTERNATIVES {
+                        lowered = Lowered {
+                            alternatives: vec![vec![self.helper(lowered.alternatives)]],
+                            nullable: lowered.nullable,
+                        };
+                    }
+                }
+                Ok(lowered)
+            }
+            Expr::Repeat {
+                expr,
+                min,
+                max,
+                pos,
+            } => self.repeat(expr, *min, *max, *pos),
+        }
+    }
+
+    fn repeat(
+        &mut self,
+        expr: &Expr,
+        min: usize,
+        max: Option<usize>,
+        pos: usize,
+    ) -> Result<Lowered> {
+        // Tokens with any or excepted characters are matched as a whole by
+        // `bnf_sampler`, so these only work when repeated without bounds.
+        let special = match expr {
+            Expr::Any(_) => Some(Symbol::Nonterminal("any!".into())),
+            Expr::Class {
+                negated: true,
+                ranges,
+                pos,
+            } => {
+                let excepted = self
+                    .class_chars(ranges, *pos)?
+                    .into_iter()
+                    .map(|c| vec![Symbol::Terminal(c.to_string())])
+                    .collect();
+                let Symbol::Nonterminal(excepted) = self.helper(excepted) else {
+                    unreachable!()
+                };
+                Some(Symbol::Nonterminal(format!("except!([{excepted}])")))
+            }
+            _ => None,
+        };
+        if let Some(symbol) = special {
+            if max.is_some() || min > 1 {
+                return Err(positioned(
+                    self.source,
+                    pos,
+                    "Negated character classes and `.` are only supported with `*` or `+`",
+                ));
+            }
+            return Ok(Lowered {
+                alternatives: vec![vec![self.one_or_more(symbol)]],
+                nullable: min == 0,
+            });
+        }
+
+        let lowered = self.lower(expr)?;
+        if lowered.alternatives.is_empty() {
+            return Ok(Lowered::empty());
+        }
+        let min = if lowered.nullable { 0 } else { min };
+        let symbol = self.symbol(lowered.alternatives);
+        let alternatives = match max {
+            None => {
+                let plus = self.one_or_more(symbol.clone());
+                vec![[vec![symbol; min.saturating_sub(1)], vec![plus]].concat()]
+            }
+            Some(0) => return Ok(Lowered::empty()),
+            Some(max) => {
+                let base = vec![symbol.clone(); min];
+                let mut alternatives = Vec::new();
+                if min > 0 {
+                    alternatives.push(base.clone());
+                }
+                if max > min {
+                    let up_to = self.up_to(symbol, max - min);
+                    alternatives.push([base, vec![up_to]].concat());
+                }
+                alternatives
+            }
+        };
+        Ok(Lowered {
+            alternatives,
+            nullable: min == 0,
+        })
+    }
+
+    /// Defines `<x+> ::= x | x <x+>`.
+    fn one_or_more(&mut self, symbol: Symbol) -> Symbol {
+        let name = self.helper_name();
+        let alternatives = vec![
+            vec![symbol.clone()],
+            vec![symbol, Symbol::Nonterminal(name.clone())],
+        ];
+        self.helper_rules.push((name.clone(), alternatives));
+        Symbol::Nonterminal(name)
+    }
+
+    /// Defines a rule matching the symbol from 1 to `count` times.
+    fn up_to(&mut self, symbol: Symbol, count: usize) -> Symbol {
+        let mut up_to = symbol.clone();
+        for _ in 1..count {
+            up_to = self.helper(vec![vec![symbol.clone()], vec![symbol.clone(), up_to]]);
+        }
+        up_to
+    }
+
+    fn class_chars(&self, ranges: &[(char, char)], pos: usize) -> Result<Vec<char>> {
+        let mut chars = Vec::new();
+        for &(from, to) in ranges {
+            let len = (to as usize).saturating_sub(from as usize) + 1;
+            if chars.len() + len > MAX_CLASS_CHARS {
+                return Err(positioned(
+                    self.source,
+                    pos,
+                    format!("Character classes can't have more than {MAX_CLASS_CHARS} characters"),
+                ));
+            }
+            chars.extend(from..=to);
+        }
+        chars.sort_unstable();
+        chars.dedup();
+        Ok(chars)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bnf_sampler::{
+        grammar::Grammar,
+        sampler::{AcceptTokenResult, Sampler},
+        utils::U8ArrayWrapper,
+        vocabulary::Vocabulary,
+    };
+    use qp_trie::Trie;
+    use rustc_hash::FxHashMap;
+
+    use super::*;
+
+    const TOKENS: [&str; 12] = [
+        "a", "b", "c", "x", "ab", "1", "2", "12", ",", "}", "A", "\"",
+    ];
+
+    fn vocabulary() -> Arc<Vocabulary> {
+        let tokens = TOKENS.iter().enumerate();
+        let token_to_id = Trie::from_iter(tokens.clone().map(|(i, x)| {
+            (
+                U8ArrayWrapper(x.as_bytes().to_vec().into_boxed_slice()),
+                i as u32,
+            )
+        }));
+        let id_to_token = FxHashMap::from_iter(
+            tokens
+                .clone()
+                .map(|(i, x)| (i as u32, x.as_bytes().to_vec())),
+        );
+        let id_to_token_string =
+            FxHashMap::from_iter(tokens.map(|(i, x)| (i as u32, x.to_string())));
+        Arc::new(Vocabulary {
+            token_to_id,
+            id_to_token,
+            id_to_token_string,
+        })
+    }
+
+    /// Translates and compiles the grammar, then feeds it the tokens, returning
+    /// whether they complete the grammar.
+    fn accepts(syntax: GrammarSyntax, grammar: &str, start: &str, tokens: &[&str]) -> bool {
+        let vocabulary = vocabulary();
+        let grammar = syntax.translate(grammar).unwrap();
+        let grammar = Grammar::new(&grammar, vocabulary.clone(), 1024).unwrap();
+        let mut sampler = Sampler::new(grammar, start.into(), vocabulary, 1 << 16, true).unwrap();
+        let mut result = AcceptTokenResult::Continue;
+        for token in tokens {
+            let id = TOKENS.iter().position(|x| x == token).unwrap() as u32;
+            result = sampler.accept_a_token(Some(id)).unwrap();
+            if !matches!(result, AcceptTokenResult::Continue) {
+                break;
+            }
+        }
+        matches!(result, AcceptTokenResult::End)
+    }
+
+    #[test]
+    fn gbnf_is_translated() {
+        let grammar = "root ::= item (\",\" item)* \"}\" # comment\nitem ::= [a-c]+ | [0-9]{1,2}";
+        let syntax = GrammarSyntax::Gbnf;
+        assert!(accepts(
+            syntax,
+            grammar,
+            "root",
+            &["a", "b", ",", "12", ",", "1", "2", "}"]
+        ));
+        assert!(!accepts(syntax, grammar, "root", &["1", "2", "1"]));
+        assert!(!accepts(syntax, grammar, "root", &["x", "}"]));
+
+        let grammar = "root ::= \"\\\"\" [^\"]* \"\\\"\"";
+        assert!(accepts(syntax, grammar, "root", &["\"", "a", "12", "\""]));
+
+        let grammar = "root ::= x? y? \"}\"\nx ::= \"a\"\ny ::= \"b\"";
+        assert!(accepts(syntax, grammar, "root", &["}"]));
+        assert!(accepts(syntax, grammar, "root", &["ab", "}"]));
+        assert!(!accepts(syntax, grammar, "root", &["b", "a"]));
+    }
+
+    #[test]
+    fn ebnf_is_translated() {
+        let grammar = "start: pair (\",\" pair)* \"}\"\n?pair: \"a\"..\"c\"~2 [\"x\"]\n// comment";
+        let syntax = GrammarSyntax::Ebnf;
+        assert!(accepts(
+            syntax,
+            grammar,
+            "start",
+            &["a", "b", "x", ",", "c", "c", "}"]
+        ));
+        assert!(!accepts(syntax, grammar, "start", &["a", "}"]));
+
+        let grammar = "start: \"ab\"i \"}\"";
+        assert!(accepts(syntax, grammar, "start", &["A", "b", "}"]));
+    }
+
+    #[test]
+    fn errors_are_positioned() {
+        let error = |syntax: GrammarSyntax, grammar: &str| {
+            syntax.translate(grammar).unwrap_err().to_string()
+        };
+        assert!(error(GrammarSyntax::Gbnf, "root ::= \"a\"{3,1}").contains("line 1, column 13"));
+        assert!(error(GrammarSyntax::Gbnf, "root ::=\n  \"a\" (b").contains("line 2, column 7"));
+        assert!(error(GrammarSyntax::Ebnf, "start: /a+/").contains("line 1"));
+        assert!(error(GrammarSyntax::Gbnf, "root ::= foo").contains("foo"));
+    }
+
+    #[test]
+    fn huge_repetitions_are_rejected() {
+        for grammar in [
+            "root ::= \"a\"{100000}",
+            "root ::= \"a\"{1,100000}",
+            "root ::= \"a\"{100000,}",
+        ] {
+            let error = GrammarSyntax::Gbnf.translate(grammar).unwrap_err();
+            assert!(error.to_string().contains("column 13"), "{error}");
+        }
+        let error = GrammarSyntax::Ebnf
+            .translate("start: \"a\"~1..100000")
+            .unwrap_err();
+        assert!(error.to_string().contains("column 11"), "{error}");
+        assert!(GrammarSyntax::Gbnf
+            .translate("root ::= \"a\"{2,256}")
+            .is_ok());
+    }
+
+    #[test]
+    fn huge_classes_are_rejected() {
+        let error = GrammarSyntax::Gbnf
+            .translate("root ::= [^\\x00-\\U0010FFFF]+")
+            .unwrap_err();
+        assert!(error.to_string().contains("Character classes"), "{error}");
+    }
+}
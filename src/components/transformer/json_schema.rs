@@ -0,0 +1,678 @@
+use anyhow::{Error, Result};
+use regex_syntax::hir::{
+    Capture, Class, ClassBytes, ClassBytesRange, ClassUnicode, ClassUnicodeRange, Hir, HirKind,
+    Look, Repetition,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
+
+use super::{regex::RegexConstraint, types::Transformer};
+
+/// A character inside a JSON string, either unescaped or escaped.
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct JsonSchemaData {
+    schema: Value,
+    /// Upper bound of DFA states to precompute allowed tokens for.
+    #[serde(default = "JsonSchemaData::default_max_states")]
+    max_states: usize,
+}
+
+impl JsonSchemaData {
+    fn default_max_states() -> usize {
+        4096
+    }
+}
+
+/// Compiles a JSON schema into a regex matching compact JSON documents.
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    /// Location of the schema being compiled, for error messages.
+    path: Vec<String>,
+    /// References being resolved, to detect recursions.
+    refs: Vec<String>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            path: Vec::new(),
+            refs: Vec::new(),
+        }
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> Error {
+        Error::msg(format!("{message} (at #/{})", self.path.join("/")))
+    }
+
+    fn nested(&mut self, key: &str, schema: &Value) -> Result<String> {
+        self.path.push(key.to_string());
+        let regex = self.compile(schema)?;
+        self.path.pop();
+        Ok(regex)
+    }
+
+    fn compile(&mut self, schema: &Value) -> Result<String> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(_) => return Err(self.error("Boolean schemas are not supported")),
+            _ => return Err(self.error("A schema must be an object")),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| self.error("enum must be an array"))?;
+            return Ok(alternate(values.iter().map(literal)));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let schemas = schemas
+                    .as_array()
+                    .ok_or_else(|| self.error(format!("{key} must be an array")))?;
+                let regexes = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| self.nested(&format!("{key}/{i}"), x))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(alternate(regexes));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            return match schemas.as_array().map(|x| x.as_slice()) {
+                Some([schema]) => self.nested("allOf/0", schema),
+                _ => Err(self.error("allOf is only supported with a single schema")),
+            };
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.typed(ty, schema),
+            Some(Value::Array(types)) => {
+                let regexes = types
+                    .iter()
+                    .map(|ty| match ty {
+                        Value::String(ty) => self.typed(ty, schema),
+                        _ => Err(self.error("type must be a string or an array of strings")),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(alternate(regexes))
+            }
+            Some(_) => Err(self.error("type must be a string or an array of strings")),
+            None if schema.contains_key("properties") => self.typed("object", schema),
+            None if schema.contains_key("items") => self.typed("array", schema),
+            None => Err(self.error("Schemas without a type are not supported")),
+        }
+    }
+
+    fn reference(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference
+            .as_str()
+            .ok_or_else(|| self.error("$ref must be a string"))?;
+        let pointer = reference
+            .strip_prefix('#')
+            .ok_or_else(|| self.error("Only local $ref starting with # is supported"))?;
+        if self.refs.iter().any(|x| x == reference) {
+            return Err(self.error(format!("Recursive $ref {reference} is not supported")));
+        }
+        let schema = self
+            .root
+            .pointer(pointer)
+            .ok_or_else(|| self.error(format!("$ref {reference} is not found")))?;
+        self.refs.push(reference.to_string());
+        let path = std::mem::replace(&mut self.path, vec![pointer.trim_start_matches('/').into()]);
+        let regex = self.compile(schema);
+        self.path = path;
+        self.refs.pop();
+        regex
+    }
+
+    fn typed(&mut self, ty: &str, schema: &Map<String, Value>) -> Result<String> {
+        match ty {
+            "null" => Ok("null".into()),
+            "boolean" => Ok("(?:true|false)".into()),
+            "string" => self.string(schema),
+            "integer" => self.integer(schema),
+            "number" => self.number(schema),
+            "array" => self.array(schema),
+            "object" => self.object(schema),
+            _ => Err(self.error(format!("Unknown type {ty}"))),
+        }
+    }
+
+    fn string(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        if let Some(pattern) = schema.get("pattern") {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| self.error("pattern must be a string"))?;
+            let pattern = regex_syntax::parse(pattern)
+                .map_err(|e| self.error(format!("Invalid pattern: {e}")))?;
+            let pattern = json_escaped(&strip_anchors(&pattern));
+            return Ok(format!("\"(?:{pattern})\""));
+        }
+        let min = self.count(schema, "minLength")?.unwrap_or(0);
+        let max = self.count(schema, "maxLength")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("maxLength must not be less than minLength"));
+        }
+        Ok(format!("\"{STRING_CHAR}{}\"", repeat(min, max)))
+    }
+
+    fn integer(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let bounds = self.bounds(schema)?;
+        let lo = bounds.lo.map(|(x, exclusive)| {
+            if exclusive {
+                x.floor() as i128 + 1
+            } else {
+                x.ceil() as i128
+            }
+        });
+        let hi = bounds.hi.map(|(x, exclusive)| {
+            if exclusive {
+                x.ceil() as i128 - 1
+            } else {
+                x.floor() as i128
+            }
+        });
+        int_range(lo, hi).ok_or_else(|| self.error("No integer is in the range"))
+    }
+
+    /// Numbers are integers, or decimals whose integer part and the next integer
+    /// are both in the bounds, so non-integer bounds are rounded inwards. A
+    /// decimal may also start from an exclusive integer bound, as long as its
+    /// fraction is not all zeros.
+    fn number(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let integer = self.integer(schema).ok();
+        let bounds = self.bounds(schema)?;
+        let exclusive_integer = |bound: Option<(f64, bool)>| {
+            bound.is_some_and(|(x, exclusive)| exclusive && x.fract() == 0.0)
+        };
+        // The decimal is in (v, v + 1), with v written as -(|v| - 1) if negative.
+        let lo = bounds.lo.map(|(x, _)| x.ceil() as i128);
+        let hi = bounds.hi.map(|(x, _)| x.floor() as i128 - 1);
+        let mut decimals = Vec::new();
+        if hi.is_none_or(|hi| hi >= 0) {
+            let from = lo.map_or(0, |lo| lo.max(0));
+            let nonzero = exclusive_integer(bounds.lo) && lo == Some(from);
+            decimals.extend(decimal_range(from, hi, nonzero));
+        }
+        if lo.is_none_or(|lo| lo < 0) {
+            let magnitude_lo = hi.map_or(0, |hi| (-hi - 1).max(0));
+            let magnitude_hi = lo.map(|lo| -lo - 1);
+            let nonzero =
+                exclusive_integer(bounds.hi) && hi.map(|hi| -hi - 1) == Some(magnitude_lo);
+            decimals.extend(
+                decimal_range(magnitude_lo, magnitude_hi, nonzero).map(|x| format!("-{x}")),
+            );
+        }
+        let decimal = (!decimals.is_empty()).then(|| alternate(decimals));
+        match (integer, decimal) {
+            (Some(integer), Some(decimal)) => Ok(alternate([integer, decimal])),
+            (Some(regex), None) | (None, Some(regex)) => Ok(regex),
+            (None, None) => Err(self.error("No number is in the range")),
+        }
+    }
+
+    fn array(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let min = self.count(schema, "minItems")?.unwrap_or(0);
+        let max = self.count(schema, "maxItems")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("maxItems must not be less than minItems"));
+        }
+        if max == Some(0) {
+            return Ok(r"\[\]".into());
+        }
+        let item = match schema.get("items") {
+            Some(items @ Value::Object(_)) => self.nested("items", items)?,
+            Some(_) => return Err(self.error("items must be a single schema")),
+            None => return Err(self.error("Arrays without items are not supported")),
+        };
+        let rest = repeat(min.saturating_sub(1), max.map(|x| x - 1));
+        match min {
+            0 => Ok(format!(r"\[(?:{item}(?:,{item}){rest})?\]")),
+            _ => Ok(format!(r"\[{item}(?:,{item}){rest}\]")),
+        }
+    }
+
+    /// Properties are generated in the order of the schema, optional ones may be
+    /// omitted, and additional properties are never generated.
+    fn object(&mut self, schema: &Map<String, Value>) -> Result<String> {
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|x| {
+                    x.as_str()
+                        .ok_or_else(|| self.error("required must be an array of strings"))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => return Err(self.error("required must be an array of strings")),
+            None => Vec::new(),
+        };
+        let empty = Map::new();
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => return Err(self.error("properties must be an object")),
+            None => &empty,
+        };
+        if let Some(name) = required.iter().find(|x| !properties.contains_key(**x)) {
+            return Err(self.error(format!(
+                "Required property {name} is not defined in properties"
+            )));
+        }
+
+        let mut fields = Vec::new();
+        for (name, property) in properties.iter() {
+            let value = self.nested(&format!("properties/{name}"), property)?;
+            let field = format!("{}:{value}", literal(&Value::String(name.clone())));
+            fields.push((field, required.contains(&name.as_str())));
+        }
+        Ok(format!(r"\{{{}\}}", fields_regex(&fields, false)))
+    }
+
+    fn count(&self, schema: &Map<String, Value>, key: &str) -> Result<Option<usize>> {
+        match schema.get(key) {
+            Some(value) => value
+                .as_u64()
+                .map(|x| Some(x as usize))
+                .ok_or_else(|| self.error(format!("{key} must be a non-negative integer"))),
+            None => Ok(None),
+        }
+    }
+
+    fn bounds(&self, schema: &Map<String, Value>) -> Result<Bounds> {
+        // Huge bounds are clamped so integers in regexes don't overflow.
+        let number = |key: &str| match schema.get(key) {
+            Some(Value::Number(x)) => Ok(x.as_f64().map(|x| x.clamp(-1e30, 1e30))),
+            Some(_) => Err(self.error(format!("{key} must be a number"))),
+            None => Ok(None),
+        };
+        // Exclusive bounds are numbers since draft 6, but booleans in draft 4.
+        let bound = |key: &str, exclusive_key: &str| -> Result<Option<(f64, bool)>> {
+            let inclusive = number(key)?.map(|x| (x, false));
+            match schema.get(exclusive_key) {
+                Some(Value::Bool(exclusive)) => Ok(inclusive.map(|(x, _)| (x, *exclusive))),
+                Some(_) => Ok(number(exclusive_key)?.map(|x| (x, true)).or(inclusive)),
+                None => Ok(inclusive),
+            }
+        };
+        Ok(Bounds {
+            lo: bound("minimum", "exclusiveMinimum")?,
+            hi: bound("maximum", "exclusiveMaximum")?,
+        })
+    }
+}
+
+/// Bounds of a number, along with whether they are exclusive.
+struct Bounds {
+    lo: Option<(f64, bool)>,
+    hi: Option<(f64, bool)>,
+}
+
+fn literal(value: &Value) -> String {
+    regex::escape(&value.to_string())
+}
+
+fn alternate(regexes: impl IntoIterator<Item = String>) -> String {
+    format!("(?:{})", regexes.into_iter().collect::<Vec<_>>().join("|"))
+}
+
+fn repeat(min: usize, max: Option<usize>) -> String {
+    match (min, max) {
+        (0, None) => "*".into(),
+        (1, None) => "+".into(),
+        (min, None) => format!("{{{min},}}"),
+        (min, Some(max)) if min == max => format!("{{{min}}}"),
+        (min, Some(max)) => format!("{{{min},{max}}}"),
+    }
+}
+
+/// Matches the fields joined by commas, where optional ones may be omitted.
+fn fields_regex(fields: &[(String, bool)], emitted: bool) -> String {
+    let Some(((field, required), rest)) = fields.split_first() else {
+        return String::new();
+    };
+    let separator = if emitted { "," } else { "" };
+    let present = format!("{separator}{field}{}", fields_regex(rest, true));
+    match (required, emitted) {
+        (true, _) => present,
+        (false, true) => format!("(?:,{field})?{}", fields_regex(rest, true)),
+        (false, false) => alternate([present, fields_regex(rest, false)]),
+    }
+}
+
+/// Matches decimals whose integer parts are in the range, returns `None` if
+/// the range is empty. If `nonzero_first`, the fraction after the lowest integer
+/// must not be all zeros, since it would equal an exclusive bound.
+fn decimal_range(lo: i128, hi: Option<i128>, nonzero_first: bool) -> Option<String> {
+    if !nonzero_first {
+        return uint_range(lo, hi).map(|x| format!(r"{x}\.\d+"));
+    }
+    if hi.is_some_and(|hi| hi < lo) {
+        return None;
+    }
+    let mut regexes = vec![format!(r"{lo}\.\d*[1-9]\d*")];
+    regexes.extend(uint_range(lo + 1, hi).map(|x| format!(r"{x}\.\d+")));
+    Some(alternate(regexes))
+}
+
+/// Removes `^` and `$` at the edges of a pattern, since the pattern is always
+/// matched against the whole string.
+fn strip_anchors(hir: &Hir) -> Hir {
+    match hir.kind() {
+        HirKind::Look(Look::Start | Look::End) => Hir::empty(),
+        HirKind::Concat(subs) => {
+            let mut subs = subs.as_slice();
+            if let Some((HirKind::Look(Look::Start), rest)) =
+                subs.split_first().map(|(x, rest)| (x.kind(), rest))
+            {
+                subs = rest;
+            }
+            if let Some((HirKind::Look(Look::End), rest)) =
+                subs.split_last().map(|(x, rest)| (x.kind(), rest))
+            {
+                subs = rest;
+            }
+            Hir::concat(subs.to_vec())
+        }
+        HirKind::Alternation(subs) => Hir::alternation(subs.iter().map(strip_anchors).collect()),
+        HirKind::Capture(Capture { sub, .. }) => strip_anchors(sub),
+        _ => hir.clone(),
+    }
+}
+
+/// Rewrites a pattern over the chars of a string into one over its JSON form,
+/// where quotes, backslashes and control chars are escaped.
+fn json_escaped(hir: &Hir) -> Hir {
+    let escaped = |chars: &mut dyn Iterator<Item = u8>| {
+        chars
+            .map(|x| Hir::literal(json_escape(x)))
+            .collect::<Vec<_>>()
+    };
+    match hir.kind() {
+        HirKind::Literal(literal) => Hir::literal(
+            literal
+                .0
+                .iter()
+                .flat_map(|&x| match x {
+                    b'"' | b'\\' | 0x00..=0x1F => json_escape(x),
+                    _ => vec![x],
+                })
+                .collect::<Vec<_>>(),
+        ),
+        HirKind::Class(Class::Unicode(class)) => {
+            let special = ClassUnicode::new([
+                ClassUnicodeRange::new('\0', '\x1F'),
+                ClassUnicodeRange::new('"', '"'),
+                ClassUnicodeRange::new('\\', '\\'),
+            ]);
+            let (mut plain, mut escapes) = (class.clone(), class.clone());
+            plain.difference(&special);
+            escapes.intersect(&special);
+            let mut chars = escapes.iter().flat_map(|x| x.start() as u8..=x.end() as u8);
+            let mut alternatives = vec![Hir::class(Class::Unicode(plain))];
+            alternatives.extend(escaped(&mut chars));
+            Hir::alternation(alternatives)
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let special = ClassBytes::new([
+                ClassBytesRange::new(0x00, 0x1F),
+                ClassBytesRange::new(b'"', b'"'),
+                ClassBytesRange::new(b'\\', b'\\'),
+            ]);
+            let (mut plain, mut escapes) = (class.clone(), class.clone());
+            plain.difference(&special);
+            escapes.intersect(&special);
+            let mut chars = escapes.iter().flat_map(|x| x.start()..=x.end());
+            let mut alternatives = vec![Hir::class(Class::Bytes(plain))];
+            alternatives.extend(escaped(&mut chars));
+            Hir::alternation(alternatives)
+        }
+        HirKind::Repetition(repetition) => Hir::repetition(Repetition {
+            sub: Box::new(json_escaped(&repetition.sub)),
+            ..repetition.clone()
+        }),
+        HirKind::Capture(Capture { sub, .. }) => json_escaped(sub),
+        HirKind::Concat(subs) => Hir::concat(subs.iter().map(json_escaped).collect()),
+        HirKind::Alternation(subs) => Hir::alternation(subs.iter().map(json_escaped).collect()),
+        HirKind::Empty | HirKind::Look(_) => hir.clone(),
+    }
+}
+
+/// The escaped form of a char in a JSON string, like `serde_json` writes it.
+fn json_escape(char: u8) -> Vec<u8> {
+    match char {
+        b'"' => br#"\""#.to_vec(),
+        b'\\' => br"\\".to_vec(),
+        0x08 => br"\b".to_vec(),
+        0x0C => br"\f".to_vec(),
+        b'\n' => br"\n".to_vec(),
+        b'\r' => br"\r".to_vec(),
+        b'\t' => br"\t".to_vec(),
+        x => format!(r"\u{x:04x}").into_bytes(),
+    }
+}
+
+/// Matches integers in the range, returns `None` if the range is empty.
+fn int_range(lo: Option<i128>, hi: Option<i128>) -> Option<String> {
+    let mut regexes = Vec::new();
+    if hi.is_none_or(|hi| hi >= 0) {
+        regexes.extend(uint_range(lo.map_or(0, |lo| lo.max(0)), hi));
+    }
+    if lo.is_none_or(|lo| lo < 0) {
+        let magnitude_lo = hi.map_or(1, |hi| (-hi).max(1));
+        let magnitude_hi = lo.map(|lo| -lo);
+        regexes.extend(uint_range(magnitude_lo, magnitude_hi).map(|x| format!("-{x}")));
+    }
+    (!regexes.is_empty()).then(|| alternate(regexes))
+}
+
+/// Matches non-negative integers without leading zeros in the range, returns
+/// `None` if the range is empty.
+fn uint_range(lo: i128, hi: Option<i128>) -> Option<String> {
+    let digits = |x: i128| x.max(1).ilog10() + 1;
+    let mut regexes = Vec::new();
+    let hi = match hi {
+        Some(hi) if hi < lo => return None,
+        Some(hi) => hi,
+        None => {
+            // Integers with more digits than the lower bound are all in range.
+            let digits = digits(lo);
+            regexes.push(format!(r"[1-9]\d{{{digits},}}"));
+            10i128.pow(digits) - 1
+        }
+    };
+    for n in digits(lo)..=digits(hi) {
+        let from = lo.max(if n == 1 { 0 } else { 10i128.pow(n - 1) });
+        let to = hi.min(10i128.pow(n) - 1);
+        regexes.extend(digit_ranges(
+            from.to_string().as_bytes(),
+            to.to_string().as_bytes(),
+        ));
+    }
+    Some(alternate(regexes))
+}
+
+/// Matches numbers of the same count of digits in the range.
+fn digit_ranges(from: &[u8], to: &[u8]) -> Vec<String> {
+    let (Some((&a, from_rest)), Some((&b, to_rest))) = (from.split_first(), to.split_first())
+    else {
+        return vec![String::new()];
+    };
+    let prefixed = |digit: u8, regexes: Vec<String>| {
+        regexes
+            .into_iter()
+            .map(move |x| format!("{}{x}", digit as char))
+    };
+    if a == b {
+        return prefixed(a, digit_ranges(from_rest, to_rest)).collect();
+    }
+
+    let n = from_rest.len();
+    let lowest = from_rest.iter().all(|&x| x == b'0');
+    let highest = to_rest.iter().all(|&x| x == b'9');
+    let mut regexes = Vec::new();
+    let mid_lo = if lowest {
+        a
+    } else {
+        regexes.extend(prefixed(a, digit_ranges(from_rest, &vec![b'9'; n])));
+        a + 1
+    };
+    let mid_hi = if highest { b } else { b - 1 };
+    if mid_lo <= mid_hi {
+        let class = match mid_lo == mid_hi {
+            true => (mid_lo as char).to_string(),
+            false => format!("[{}-{}]", mid_lo as char, mid_hi as char),
+        };
+        let rest = match n {
+            0 => String::new(),
+            1 => r"\d".into(),
+            n => format!(r"\d{{{n}}}"),
+        };
+        regexes.push(format!("{class}{rest}"));
+    }
+    if !highest {
+        regexes.extend(prefixed(b, digit_ranges(&vec![b'0'; n], to_rest)));
+    }
+    regexes
+}
+
+/// Constrains the output to a compact JSON document valid against a schema.
+///
+/// The schema is compiled into a regex, so recursive schemas are not supported.
+#[derive(Debug, Clone)]
+pub struct JsonSchemaConstraint {
+    data: JsonSchemaData,
+    regex: RegexConstraint,
+}
+
+impl JsonSchemaConstraint {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data = serde_json::from_value::<JsonSchemaData>(data.ok_or(Error::msg(
+            "Invalid JSON schema data. Example format:{
+                schema: Object,
+                max_states: Option<usize>,
+            }",
+        ))?)?;
+        let regex = SchemaCompiler::new(&data.schema).compile(&data.schema)?;
+        let regex = RegexConstraint::new(&state, regex, data.max_states)?;
+        Ok(Box::new(JsonSchemaConstraint { data, regex }))
+    }
+}
+
+impl Transformer for JsonSchemaConstraint {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        self.regex.update(prompt)
+    }
+
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        self.regex.transform(logits)
+    }
+
+    fn clear(&mut self) {
+        self.regex.clear()
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("json_schema", &self.data)?.with_state(self.regex.history())
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        self.regex.restore(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn matcher(schema: Value) -> regex::Regex {
+        let regex = SchemaCompiler::new(&schema).compile(&schema).unwrap();
+        regex::Regex::new(&format!("^(?:{regex})$")).unwrap()
+    }
+
+    #[test]
+    fn patterns_match_escaped_strings() {
+        let regex = matcher(json!({"type": "string", "pattern": r#"^a["\\\n]+\$$"#}));
+        for text in [r#""a\"$""#, r#""a\\\n$""#] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in [r#""a"$""#, "\"a\n$\"", r#""a\"""#] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn pattern_classes_exclude_raw_specials() {
+        let regex = matcher(json!({"type": "string", "pattern": "^.*$"}));
+        assert!(regex.is_match(r#""say \"hi\"\t\u0001""#));
+        assert!(!regex.is_match(r#""say "hi"""#));
+        assert!(!regex.is_match("\"\t\""));
+    }
+
+    #[test]
+    fn exclusive_integer_bounds_exclude_zero_fractions() {
+        let regex = matcher(json!({"type": "number", "exclusiveMinimum": 1, "maximum": 3}));
+        for text in ["1.5", "1.01", "2", "2.0", "3"] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in ["1", "1.0", "1.00", "0.5", "3.5"] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+
+        let regex = matcher(json!({"type": "number", "exclusiveMaximum": 0}));
+        for text in ["-1", "-0.5", "-10.25"] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in ["0", "-0.0", "-0.000", "0.5"] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn integer_ranges_are_exact() {
+        let regex = matcher(json!({"type": "integer", "minimum": -12, "maximum": 105}));
+        for x in -30..=130 {
+            assert_eq!(
+                regex.is_match(&x.to_string()),
+                (-12..=105).contains(&x),
+                "{x}"
+            );
+        }
+    }
+
+    #[test]
+    fn optional_properties_keep_order() {
+        let regex = matcher(json!({
+            "type": "object",
+            "properties": {
+                "b": {"type": "boolean"},
+                "a": {"type": "integer", "minimum": 0},
+            },
+            "required": ["a"],
+        }));
+        for text in [r#"{"b":true,"a":1}"#, r#"{"a":0}"#] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in [r#"{"a":1,"b":true}"#, r#"{"b":false}"#, "{}"] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{app::AppState, components::ComponentDump};
+
+use super::types::Transformer;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum BiasMode {
+    #[default]
+    Add,
+    Multiply,
+}
+
+/// Which tokens of a string are biased.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StringTokens {
+    First,
+    #[default]
+    All,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct LogitBiasData {
+    #[serde(default)]
+    tokens: HashMap<u16, f32>,
+    #[serde(default)]
+    strings: HashMap<String, f32>,
+    #[serde(default)]
+    string_tokens: StringTokens,
+    #[serde(default)]
+    mode: BiasMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    data: LogitBiasData,
+    /// Biases of all tokens from both ids and strings, combined by the mode.
+    biases: Vec<(u16, f32)>,
+}
+
+impl LogitBias {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data: LogitBiasData = serde_json::from_value(data.ok_or(Error::msg(
+            "Invalid logit bias data. Example format:{
+                tokens: Option<{ [token_id]: f32 }>,
+                strings: Option<{ [string]: f32 }>,
+                string_tokens: Option<\"first\" | \"all\">,
+                mode: Option<\"Add\" | \"Multiply\">,
+            }",
+        ))?)?;
+        // Values too large for `f32` are parsed as infinity.
+        let biased = data
+            .tokens
+            .iter()
+            .map(|(token, &bias)| (format!("token {token}"), bias))
+            .chain(
+                data.strings
+                    .iter()
+                    .map(|(string, &bias)| (format!("string {string:?}"), bias)),
+            );
+        for (name, bias) in biased {
+            if !bias.is_finite() {
+                return Err(Error::msg(format!("Bias of {name} must be finite!")));
+            }
+            if data.mode == BiasMode::Multiply && bias <= 0.0 {
+                return Err(Error::msg(format!(
+                    "Bias of {name} must be larger than 0 in Multiply mode!"
+                )));
+            }
+        }
+
+        let mut biases = HashMap::new();
+        let mut combine = |token: u16, bias: f32| {
+            let entry = biases.entry(token).or_insert(match data.mode {
+                BiasMode::Add => 0.0,
+                BiasMode::Multiply => 1.0,
+            });
+            match data.mode {
+                BiasMode::Add => *entry += bias,
+                BiasMode::Multiply => *entry *= bias,
+            }
+            // Combined biases may still overflow, or underflow to 0 when multiplied.
+            match data.mode {
+                BiasMode::Add => entry.is_finite(),
+                BiasMode::Multiply => entry.is_finite() && *entry > 0.0,
+            }
+        };
+        let overflow =
+            |name: String| Error::msg(format!("Combined bias of token {name} is out of range!"));
+        for (&token, &bias) in data.tokens.iter() {
+            if !combine(token, bias) {
+                return Err(overflow(token.to_string()));
+            }
+        }
+        for (string, &bias) in data.strings.iter() {
+            let tokens = state.tokenize(&string.as_bytes().to_vec())?;
+            let tokens = match data.string_tokens {
+                StringTokens::First => &tokens[..tokens.len().min(1)],
+                StringTokens::All => &tokens[..],
+            };
+            for &token in tokens {
+                if !combine(token, bias) {
+                    return Err(overflow(format!("{token} from string {string:?}")));
+                }
+            }
+        }
+
+        Ok(Box::new(LogitBias {
+            biases: biases.into_iter().collect(),
+            data,
+        }))
+    }
+}
+
+impl Transformer for LogitBias {
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        let mut logits = logits;
+        for &(token, bias) in self.biases.iter() {
+            let logit = &mut logits[token as usize];
+            match self.data.mode {
+                BiasMode::Add => *logit += bias,
+                BiasMode::Multiply => {
+                    if *logit >= 0.0 {
+                        *logit *= bias
+                    } else {
+                        *logit /= bias
+                    }
+                }
+            }
+            // Biased logits stay finite, so the probs are never NaN.
+            *logit = logit.clamp(-f32::MAX, f32::MAX);
+        }
+        logits
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("logit_bias", &self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logit_bias(mode: BiasMode, biases: Vec<(u16, f32)>) -> LogitBias {
+        LogitBias {
+            data: LogitBiasData {
+                tokens: HashMap::new(),
+                strings: HashMap::new(),
+                string_tokens: StringTokens::All,
+                mode,
+            },
+            biases,
+        }
+    }
+
+    #[test]
+    fn biased_logits_stay_finite() {
+        let transformer = logit_bias(BiasMode::Add, vec![(0, f32::MAX), (1, -f32::MAX)]);
+        let logits = transformer.transform(vec![f32::MAX, -1e30, 1.0]);
+        assert_eq!(logits, vec![f32::MAX, -f32::MAX, 1.0]);
+
+        let transformer = logit_bias(BiasMode::Multiply, vec![(0, 1e-30), (1, 1e30)]);
+        let logits = transformer.transform(vec![-1e30, 1e30]);
+        assert_eq!(logits, vec![-f32::MAX, f32::MAX]);
+    }
+}
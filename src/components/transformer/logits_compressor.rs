@@ -1,13 +1,13 @@
 use anyhow::{Error, Result};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::app::AppState;
+use crate::{app::AppState, components::ComponentDump};
 
 use super::types::Transformer;
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct LogitsCompressor {
     factor: f32,
 }
@@ -29,4 +29,8 @@ impl Transformer for LogitsCompressor {
     fn clone(&self) -> Box<dyn Transformer> {
         Box::new(*self)
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("logits_compressor", self)
+    }
 }
@@ -1,6 +1,14 @@
+pub mod ban_phrases;
 pub mod bnf_constraint;
 pub mod disable_tokens;
+pub mod dry;
 pub mod global_penalty;
+pub mod grammar_cache;
+pub mod grammar_syntax;
+pub mod json_schema;
+pub mod logit_bias;
 pub mod logits_compressor;
+pub mod regex;
 pub mod sliding_penalty;
 pub mod types;
+pub mod watermark;
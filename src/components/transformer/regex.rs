@@ -0,0 +1,336 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::{Error, Result};
+use bit_set::BitSet;
+use regex_automata::{
+    dfa::{dense, Automaton, StartKind},
+    util::{primitives::StateID, start},
+    Anchored,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
+
+use super::types::{Transformer, MASKED_LOGIT};
+
+/// The end of text token, which is allowed whenever the output matches.
+const EOS: u16 = 0;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RegexData {
+    regex: String,
+    /// Upper bound of DFA states to precompute allowed tokens for.
+    #[serde(default = "RegexData::default_max_states")]
+    max_states: usize,
+}
+
+impl RegexData {
+    fn default_max_states() -> usize {
+        1024
+    }
+}
+
+/// A regex compiled into a DFA, along with the tokens allowed in each state
+/// reachable from the start.
+#[derive(Debug)]
+struct CompiledRegex {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    id_to_token: HashMap<u16, Vec<u8>>,
+    allowed: HashMap<StateID, BitSet>,
+}
+
+impl CompiledRegex {
+    fn new(data: &RegexData, id_to_token: HashMap<u16, Vec<u8>>) -> Result<Self> {
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().start_kind(StartKind::Anchored))
+            .build(&data.regex)?;
+        let start = dfa.start_state(&start::Config::new().anchored(Anchored::Yes))?;
+
+        let mut compiled = Self {
+            dfa,
+            start,
+            id_to_token,
+            allowed: HashMap::new(),
+        };
+        let mut transitions: HashMap<StateID, Vec<(u16, StateID)>> = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(state) = queue.pop_front() {
+            if transitions.contains_key(&state) {
+                continue;
+            }
+            if transitions.len() == data.max_states {
+                return Err(Error::msg(format!(
+                    "The regex reaches more than {} DFA states, increase data.max_states or simplify the regex.",
+                    data.max_states
+                )));
+            }
+            let next_states = compiled
+                .id_to_token
+                .keys()
+                .filter_map(|&token| Some((token, compiled.advance(state, token)?)))
+                .collect::<Vec<_>>();
+            queue.extend(
+                next_states
+                    .iter()
+                    .map(|(_, next)| *next)
+                    .filter(|next| !transitions.contains_key(next)),
+            );
+            transitions.insert(state, next_states);
+        }
+
+        // A state the DFA doesn't die in may still be a dead end, such as the
+        // one after a byte past a complete match, so tokens leading to states
+        // which neither accept nor continue are pruned until none is left.
+        loop {
+            let dead_ends = transitions
+                .iter()
+                .filter(|(state, next_states)| {
+                    next_states.is_empty() && !compiled.is_accepting(**state)
+                })
+                .map(|(state, _)| *state)
+                .collect::<HashSet<_>>();
+            let mut pruned = false;
+            for next_states in transitions.values_mut() {
+                let len = next_states.len();
+                next_states.retain(|(_, next)| !dead_ends.contains(next));
+                pruned |= next_states.len() != len;
+            }
+            if !pruned {
+                break;
+            }
+        }
+        compiled.allowed = transitions
+            .into_iter()
+            .map(|(state, next_states)| {
+                let allowed = next_states
+                    .into_iter()
+                    .map(|(token, _)| token as usize)
+                    .collect::<BitSet>();
+                (state, allowed)
+            })
+            .collect();
+        if compiled.allowed[&start].is_empty() && !compiled.is_accepting(start) {
+            return Err(Error::msg("No token sequence can match the regex."));
+        }
+        Ok(compiled)
+    }
+
+    /// Feeds the bytes of the token, returns `None` if the DFA dies.
+    fn advance(&self, state: StateID, token: u16) -> Option<StateID> {
+        let mut state = state;
+        for &byte in self.id_to_token.get(&token)? {
+            state = self.dfa.next_state(state, byte);
+            if self.dfa.is_dead_state(state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// Matches are delayed by a byte in the DFA, so it's checked at the end of input.
+    fn is_accepting(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+    }
+}
+
+/// Constrains the output to match a regex from the start.
+#[derive(Debug, Clone)]
+pub struct RegexConstraint {
+    data: RegexData,
+    regex: Arc<CompiledRegex>,
+    state: StateID,
+    /// Tokens accepted since the last clear, the DFA state is restored by
+    /// replaying them.
+    history: Vec<u16>,
+}
+
+impl RegexConstraint {
+    pub fn initialize(state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data = serde_json::from_value::<RegexData>(data.ok_or(Error::msg(
+            "Invalid regex data. Example format:{
+                regex: String,
+                max_states: Option<usize>,
+            }",
+        ))?)?;
+        Ok(Box::new(Self::new(&state, data.regex, data.max_states)?))
+    }
+
+    /// Compiles the regex, which is also used by other transformers that build
+    /// their constraints upon regexes.
+    pub(super) fn new(state: &AppState, regex: String, max_states: usize) -> Result<Self> {
+        let data = RegexData { regex, max_states };
+        let id_to_token = state
+            .0
+            .tokenizer
+            .bytes_to_token_index()
+            .iter()
+            .map(|(k, v)| (*v, k.clone()))
+            .collect();
+        // Precomputing walks the whole vocabulary for each DFA state, so other
+        // tasks are moved off the worker meanwhile.
+        let regex = tokio::task::block_in_place(|| CompiledRegex::new(&data, id_to_token))?;
+        Ok(RegexConstraint {
+            data,
+            state: regex.start,
+            regex: Arc::new(regex),
+            history: Vec::new(),
+        })
+    }
+
+    /// Tokens accepted since the last clear.
+    pub(super) fn history(&self) -> &Vec<u16> {
+        &self.history
+    }
+}
+
+impl Transformer for RegexConstraint {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        for token_id in prompt {
+            if *token_id == EOS && self.regex.is_accepting(self.state) {
+                return Err(InferenceInterruption::Exhaustion);
+            }
+            self.state = self.regex.advance(self.state, *token_id).ok_or_else(|| {
+                InferenceInterruption::Error(anyhow::anyhow!(
+                    "Token {token_id} is rejected by regex."
+                ))
+            })?;
+            self.history.push(*token_id);
+        }
+        if self.regex.allowed[&self.state].is_empty() {
+            return if self.regex.is_accepting(self.state) {
+                Err(InferenceInterruption::Exhaustion)
+            } else {
+                Err(InferenceInterruption::Error(Error::msg(
+                    "No token can continue the regex.",
+                )))
+            };
+        }
+        Ok(())
+    }
+
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        let mut logits = logits;
+        let allowed = &self.regex.allowed[&self.state];
+        let accepting = self.regex.is_accepting(self.state);
+        for (i, logit) in logits.iter_mut().enumerate() {
+            let eos = accepting && i == EOS as usize;
+            if !(allowed.contains(i) || eos) {
+                *logit = MASKED_LOGIT;
+            }
+        }
+        logits
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+        self.state = self.regex.start;
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("regex", &self.data)?.with_state(&self.history)
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let history: Vec<u16> = serde_json::from_value(state)?;
+        self.clear();
+        match self.update(&history) {
+            Ok(_) | Err(InferenceInterruption::Exhaustion) => Ok(()),
+            Err(InferenceInterruption::Error(e)) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A vocabulary of single digits, `-` and `12`, with EOS as token 0.
+    fn vocabulary() -> HashMap<u16, Vec<u8>> {
+        let mut tokens: HashMap<u16, Vec<u8>> =
+            (0..10u8).map(|x| (x as u16 + 1, vec![b'0' + x])).collect();
+        tokens.insert(11, b"-".to_vec());
+        tokens.insert(12, b"12".to_vec());
+        tokens
+    }
+
+    fn compile(regex: &str) -> Result<CompiledRegex> {
+        let data = RegexData {
+            regex: regex.to_string(),
+            max_states: RegexData::default_max_states(),
+        };
+        CompiledRegex::new(&data, vocabulary())
+    }
+
+    fn walk(regex: &CompiledRegex, tokens: &[u16]) -> Option<StateID> {
+        tokens
+            .iter()
+            .try_fold(regex.start, |state, &token| regex.advance(state, token))
+    }
+
+    #[test]
+    fn tokens_follow_the_regex() {
+        let regex = compile(r"\d{2}-\d").unwrap();
+        let allowed = &regex.allowed[&regex.start];
+        assert!(allowed.contains(2) && allowed.contains(12) && !allowed.contains(11));
+
+        let state = walk(&regex, &[12]).unwrap();
+        assert_eq!(regex.allowed[&state].iter().collect::<Vec<_>>(), vec![11]);
+        let state = walk(&regex, &[12, 11, 5]).unwrap();
+        assert!(regex.is_accepting(state));
+        assert!(regex.allowed[&state].is_empty());
+    }
+
+    #[test]
+    fn dead_ends_are_pruned() {
+        // `12` would leave no room for the `-`.
+        let regex = compile(r"\d-").unwrap();
+        assert!(!regex.allowed[&regex.start].contains(12));
+    }
+
+    #[test]
+    fn eos_is_allowed_when_accepting() {
+        let regex = Arc::new(compile(r"\d+").unwrap());
+        let mut constraint = RegexConstraint {
+            data: RegexData {
+                regex: String::new(),
+                max_states: 0,
+            },
+            state: regex.start,
+            regex,
+            history: Vec::new(),
+        };
+        let logits = constraint.transform(vec![0.0; 13]);
+        assert_eq!(logits[EOS as usize], MASKED_LOGIT);
+
+        assert!(constraint.update(&vec![3]).is_ok());
+        let logits = constraint.transform(vec![0.0; 13]);
+        assert_eq!(logits[EOS as usize], 0.0);
+        assert_eq!(logits[4], 0.0);
+        assert!(matches!(
+            constraint.update(&vec![EOS]),
+            Err(InferenceInterruption::Exhaustion)
+        ));
+    }
+
+    #[test]
+    fn unmatchable_regex_is_rejected() {
+        assert!(compile("[a-z]").is_err());
+        let data = RegexData {
+            regex: r"\d{16}".to_string(),
+            max_states: 8,
+        };
+        assert!(CompiledRegex::new(&data, vocabulary()).is_err());
+    }
+}
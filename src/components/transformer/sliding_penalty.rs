@@ -1,14 +1,17 @@
 use std::collections::VecDeque;
 
 use anyhow::{Error, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{app::AppState, components::InferenceInterruption};
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
 
-use super::types::{penalty_transform, PenaltyMode, Transformer};
+use super::types::{penalty_transform, PenaltyMode, SparseRecord, Transformer};
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 struct PenaltyData {
     alpha_occurrence: f32,
     alpha_presence: f32,
@@ -17,6 +20,13 @@ struct PenaltyData {
     mode: PenaltyMode,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct PenaltyState {
+    record: SparseRecord,
+    presence: SparseRecord,
+    history: VecDeque<u16>,
+}
+
 #[derive(Debug)]
 pub struct SlidingPenalty {
     data: PenaltyData,
@@ -80,6 +90,34 @@ impl Transformer for SlidingPenalty {
             history: self.history.clone(),
         })
     }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        let default = match self.data.mode {
+            PenaltyMode::Subtract => 0.0,
+            PenaltyMode::Divide => 1.0,
+        };
+        ComponentDump::new("sliding_penalty", &self.data)?.with_state(&PenaltyState {
+            record: SparseRecord::new(&self.record, default),
+            presence: SparseRecord::new(&self.presence, default),
+            history: self.history.clone(),
+        })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let PenaltyState {
+            record,
+            presence,
+            history,
+        } = serde_json::from_value(state)?;
+        let default = match self.data.mode {
+            PenaltyMode::Subtract => 0.0,
+            PenaltyMode::Divide => 1.0,
+        };
+        self.record = record.into_dense(default);
+        self.presence = presence.into_dense(default);
+        self.history = history;
+        Ok(())
+    }
 }
 
 pub fn initialize_sliding(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
@@ -1,9 +1,10 @@
 use anyhow::{Error, Result};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt::Debug;
 
-use crate::components::InferenceInterruption;
+use crate::components::{ComponentDump, InferenceInterruption};
 
 /// Transforms a logits distribution.
 ///
@@ -19,6 +20,10 @@ use crate::components::InferenceInterruption;
 /// `Transformers`.
 ///
 /// Refer to `GlobalPenalty` for a complete example of transformer implementation.
+/// Logit of tokens which are masked out. It's far below any real logit, but
+/// leaves room for later transformers adding to it without overflowing.
+pub const MASKED_LOGIT: f32 = -1e30;
+
 pub trait Transformer: Send + Sync + Debug {
     ///Updates the internal state of the transformer by accepting a list of tokens.
     ///
@@ -49,6 +54,19 @@ pub trait Transformer: Send + Sync + Debug {
     /// You can retain a part of the data (by using Arc, etc). As long as you are sure that those
     /// shared data are *immutable*, or any multi-threaded write access is *controlled*.
     fn clone(&self) -> Box<dyn Transformer>;
+    /// Dumps the construction params along with the internal state of the `Transformer`,
+    /// so it can be persisted and restored later.
+    ///
+    /// The `type_id` of the dump must be the one the `Transformer` is registered with.
+    fn dump(&self) -> Result<ComponentDump>;
+    /// Restores the internal state from a dump, after the `Transformer` is constructed
+    /// from the dumped params.
+    ///
+    /// By default the `Transformer` has no internal state, so nothing is restored.
+    #[allow(unused_variables)]
+    fn restore(&mut self, state: Value) -> Result<()> {
+        Ok(())
+    }
     /// Implements a special update for prompt, where it will not throw InferenceInterruption::Exhaustion
     /// if the exhaustion happened. This is for special components like BNF which might exhaust when
     /// reading prompt.
@@ -65,13 +83,39 @@ pub trait Transformer: Send + Sync + Debug {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Default, Copy, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, Copy, PartialEq)]
 pub enum PenaltyMode {
     #[default]
     Subtract,
     Divide,
 }
 
+/// A penalty record where most of the values are the default, so only the
+/// others are kept when dumped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SparseRecord(Vec<(u16, f32)>);
+
+impl SparseRecord {
+    pub fn new(record: &[f32], default: f32) -> Self {
+        Self(
+            record
+                .iter()
+                .enumerate()
+                .filter(|(_, &x)| x != default)
+                .map(|(index, &x)| (index as u16, x))
+                .collect(),
+        )
+    }
+
+    pub fn into_dense(self, default: f32) -> Vec<f32> {
+        let mut record = vec![default; 65536];
+        for (index, x) in self.0 {
+            record[index as usize] = x;
+        }
+        record
+    }
+}
+
 pub fn penalty_transform(
     mode: PenaltyMode,
     logits: Vec<f32>,
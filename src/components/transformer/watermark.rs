@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    app::AppState,
+    components::{ComponentDump, InferenceInterruption},
+};
+
+use super::types::Transformer;
+
+/// How the green list of each step is drawn, which must be the same for
+/// generating and detecting a watermark.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatermarkScheme {
+    /// The secret key seeding the green lists.
+    key: String,
+    /// Fraction of the vocabulary in the green list.
+    #[serde(default = "WatermarkScheme::default_gamma")]
+    gamma: f32,
+    /// Count of previous tokens seeding the green list.
+    #[serde(default = "WatermarkScheme::default_context_width")]
+    context_width: usize,
+}
+
+/// Statistics of green tokens in a text.
+#[derive(Debug, Serialize)]
+pub struct WatermarkDetection {
+    /// Tokens with a complete context, the first `context_width` tokens are
+    /// not scored.
+    pub scored_tokens: usize,
+    pub green_tokens: usize,
+    pub z_score: f64,
+    /// Probability of having at least as many green tokens without the watermark.
+    pub p_value: f64,
+}
+
+impl WatermarkScheme {
+    fn default_gamma() -> f32 {
+        0.25
+    }
+
+    fn default_context_width() -> usize {
+        1
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.key.is_empty() {
+            return Err(Error::msg("key must not be empty!"));
+        }
+        if !(self.gamma > 0.0 && self.gamma < 1.0) {
+            return Err(Error::msg("gamma must be between 0 and 1!"));
+        }
+        if self.context_width == 0 {
+            return Err(Error::msg("context_width must be larger than 0!"));
+        }
+        Ok(())
+    }
+
+    /// Seed of the green list following the context. Hashes are implemented
+    /// here instead of using `std`, so they're stable across builds and a
+    /// text can be detected by any server with the key.
+    fn seed(&self, context: impl Iterator<Item = u16>) -> u64 {
+        let key = self.key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        context.fold(mix(key), |seed, token| mix(seed ^ token as u64))
+    }
+
+    fn is_green(&self, seed: u64, token: u16) -> bool {
+        let value = (mix(seed ^ token as u64) >> 11) as f64 / (1u64 << 53) as f64;
+        value < self.gamma as f64
+    }
+
+    /// Counts the green tokens of a text, each one against the green list
+    /// seeded by the tokens before it.
+    pub fn detect(&self, tokens: &[u16]) -> Result<WatermarkDetection> {
+        let scored_tokens = tokens.len().saturating_sub(self.context_width);
+        if scored_tokens == 0 {
+            return Err(Error::msg(format!(
+                "The text must be longer than {} tokens to detect a watermark!",
+                self.context_width
+            )));
+        }
+        let green_tokens = tokens
+            .windows(self.context_width + 1)
+            .filter(|window| {
+                let (token, context) = window.split_last().unwrap();
+                self.is_green(self.seed(context.iter().copied()), *token)
+            })
+            .count();
+
+        let gamma = self.gamma as f64;
+        let count = scored_tokens as f64;
+        let z_score =
+            (green_tokens as f64 - gamma * count) / (count * gamma * (1.0 - gamma)).sqrt();
+        Ok(WatermarkDetection {
+            scored_tokens,
+            green_tokens,
+            z_score,
+            p_value: 0.5 * erfc(z_score / std::f64::consts::SQRT_2),
+        })
+    }
+}
+
+/// Finalizer of SplitMix64.
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Complementary error function, with a fractional error below 1.2e-7.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct WatermarkData {
+    #[serde(flatten)]
+    scheme: WatermarkScheme,
+    /// Bias added to the logits of green tokens.
+    #[serde(default = "WatermarkData::default_delta")]
+    delta: f32,
+}
+
+impl WatermarkData {
+    fn default_delta() -> f32 {
+        2.0
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.scheme.validate()?;
+        if !(self.delta >= 0.0 && self.delta.is_finite()) {
+            return Err(Error::msg("delta must be finite and not less than 0!"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatermarkState {
+    context: VecDeque<u16>,
+}
+
+/// Watermarks the generation by biasing a green list of tokens in each step,
+/// drawn from the secret key and the previous tokens.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    data: WatermarkData,
+    /// The previous tokens, nothing is biased until there are enough of them.
+    context: VecDeque<u16>,
+}
+
+impl Watermark {
+    pub fn initialize(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+        let data: WatermarkData = serde_json::from_value(data.ok_or(Error::msg(
+            "Invalid watermark data. Example format:{
+                key: String,
+                gamma: Option<f32>,
+                delta: Option<f32>,
+                context_width: Option<usize>,
+            }",
+        ))?)?;
+        data.validate()?;
+        Ok(Box::new(Watermark {
+            context: VecDeque::with_capacity(data.scheme.context_width),
+            data,
+        }))
+    }
+}
+
+impl Transformer for Watermark {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        for &token in prompt {
+            if self.context.len() == self.data.scheme.context_width {
+                self.context.pop_front();
+            }
+            self.context.push_back(token);
+        }
+        Ok(())
+    }
+
+    fn transform(&self, logits: Vec<f32>) -> Vec<f32> {
+        let scheme = &self.data.scheme;
+        if self.context.len() < scheme.context_width {
+            return logits;
+        }
+        let mut logits = logits;
+        let seed = scheme.seed(self.context.iter().copied());
+        for (token, logit) in logits.iter_mut().enumerate() {
+            if scheme.is_green(seed, token as u16) {
+                *logit += self.data.delta;
+            }
+        }
+        logits
+    }
+
+    fn clear(&mut self) {
+        self.context.clear();
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn dump(&self) -> Result<ComponentDump> {
+        ComponentDump::new("watermark", &self.data)?.with_state(&WatermarkState {
+            context: self.context.clone(),
+        })
+    }
+
+    fn restore(&mut self, state: Value) -> Result<()> {
+        let WatermarkState { context } = serde_json::from_value(state)?;
+        self.context = context;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn scheme() -> WatermarkScheme {
+        WatermarkScheme {
+            key: "secret".into(),
+            gamma: 0.25,
+            context_width: 2,
+        }
+    }
+
+    #[test]
+    fn erfc_is_accurate() {
+        for (x, expected) in [
+            (0.0, 1.0),
+            (0.5, 0.4795001222),
+            (1.0, 0.1572992071),
+            (2.0, 0.0046777350),
+            (-1.0, 1.8427007929),
+        ] {
+            let error = (erfc(x) - expected).abs() / expected;
+            assert!(error < 1.2e-7, "erfc({x}) = {}", erfc(x));
+        }
+    }
+
+    #[test]
+    fn green_lists_follow_gamma() {
+        let scheme = scheme();
+        let seed = scheme.seed([1, 2].into_iter());
+        assert_eq!(seed, scheme.seed([1, 2].into_iter()));
+        assert_ne!(seed, scheme.seed([2, 1].into_iter()));
+        let green = (0..=u16::MAX).filter(|&x| scheme.is_green(seed, x)).count();
+        let fraction = green as f32 / 65536.0;
+        assert!((fraction - 0.25).abs() < 0.01, "{fraction}");
+    }
+
+    #[test]
+    fn watermarked_texts_are_detected() {
+        let scheme = scheme();
+        // Always picks the first green token, like a large `delta` would.
+        let mut tokens = vec![7, 8];
+        while tokens.len() < 100 {
+            let seed = scheme.seed(tokens[tokens.len() - 2..].iter().copied());
+            tokens.push((0..).find(|&x| scheme.is_green(seed, x)).unwrap());
+        }
+        let detection = scheme.detect(&tokens).unwrap();
+        assert_eq!(detection.scored_tokens, 98);
+        assert_eq!(detection.green_tokens, 98);
+        assert!(detection.p_value < 1e-20, "{detection:?}");
+
+        let tokens = (0..100)
+            .map(|x| (x * 7919 % 65536) as u16)
+            .collect::<Vec<_>>();
+        let detection = scheme.detect(&tokens).unwrap();
+        assert!(detection.z_score.abs() < 4.0, "{detection:?}");
+        assert!(detection.p_value > 1e-4, "{detection:?}");
+
+        assert!(scheme.detect(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        let data = |value: Value| serde_json::from_value::<WatermarkData>(value).unwrap();
+        assert!(data(json!({"key": "secret"})).validate().is_ok());
+        for value in [
+            json!({"key": "secret", "delta": -1.0}),
+            json!({"key": "secret", "delta": 1e39}),
+            json!({"key": "secret", "gamma": 1.0}),
+            json!({"key": ""}),
+        ] {
+            assert!(data(value.clone()).validate().is_err(), "{value}");
+        }
+        let nan = WatermarkData {
+            delta: f32::NAN,
+            ..data(json!({"key": "secret"}))
+        };
+        assert!(nan.validate().is_err());
+    }
+
+    #[test]
+    fn only_green_tokens_are_biased() {
+        let data = WatermarkData {
+            scheme: scheme(),
+            delta: 2.0,
+        };
+        let mut watermark = Watermark {
+            context: VecDeque::new(),
+            data,
+        };
+        watermark.update(&vec![1]).ok();
+        assert_eq!(watermark.transform(vec![0.0; 16]), vec![0.0; 16]);
+
+        watermark.update(&vec![5, 1, 2]).ok();
+        let seed = watermark.data.scheme.seed([1, 2].into_iter());
+        for (token, logit) in watermark.transform(vec![0.0; 256]).into_iter().enumerate() {
+            let expected = match watermark.data.scheme.is_green(seed, token as u16) {
+                true => 2.0,
+                false => 0.0,
+            };
+            assert_eq!(logit, expected);
+        }
+    }
+}
@@ -12,6 +12,7 @@ use tokio::{
 };
 
 use serde::Deserialize;
+use serde_json::Value;
 use web_rwkv::{
     context::{Context, ContextBuilder, Instance},
     model::{
@@ -251,6 +252,45 @@ impl TokenizerSpec {
 #[derive(Debug, Deserialize, Clone)]
 pub struct AxumSpec {
     pub state_dump: PathBuf,
+    pipeline_dump: Option<PathBuf>,
+    grammar_cache_size: Option<usize>,
+    grammar_cache_max_mb: Option<usize>,
+}
+
+impl AxumSpec {
+    pub fn get_pipeline_dump(&self) -> PathBuf {
+        self.pipeline_dump
+            .clone()
+            .unwrap_or_else(|| self.state_dump.join("pipelines"))
+    }
+
+    pub fn get_grammar_cache_size(&self) -> usize {
+        self.grammar_cache_size.unwrap_or(16)
+    }
+
+    pub fn get_grammar_cache_max_bytes(&self) -> usize {
+        self.grammar_cache_max_mb
+            .unwrap_or(256)
+            .saturating_mul(1024 * 1024)
+    }
+}
+
+/// A state created when the server starts, from a dump and/or a prompt.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PreloadState {
+    pub id: String,
+    pub dump_id: Option<String>,
+    pub prompt: Option<String>,
+}
+
+/// States and pipelines created when the server starts, which are read-only.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PreloadSpec {
+    /// Payloads same as `create_pipeline`.
+    #[serde(default)]
+    pub pipelines: Vec<Value>,
+    #[serde(default)]
+    pub states: Vec<PreloadState>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -258,4 +298,6 @@ pub struct ModelConfig {
     pub model: ModelSpec,
     pub tokenizer: TokenizerSpec,
     pub axum: AxumSpec,
+    #[serde(default)]
+    pub preload: PreloadSpec,
 }